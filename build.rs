@@ -0,0 +1,28 @@
+//! Build script exposing the git commit the registry is built from, as
+//! `GIT_HASH`, to the `/v2/_info` endpoint
+
+use std::env;
+use std::process::Command;
+
+/// Shown when the registry isn't built from a git checkout
+const UNKNOWN_GIT_HASH: &str = "unknown";
+
+fn main() {
+    // builds without the repository, e.g. in a container, can pass it along
+    let hash = env::var("GIT_HASH").ok().or_else(|| {
+        Command::new("git")
+            .args(["rev-parse", "--short", "HEAD"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| String::from_utf8(output.stdout).ok())
+            .map(|hash| hash.trim().to_string())
+    });
+    println!(
+        "cargo:rustc-env=GIT_HASH={}",
+        hash.as_deref().unwrap_or(UNKNOWN_GIT_HASH)
+    );
+    println!("cargo:rerun-if-env-changed=GIT_HASH");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+}
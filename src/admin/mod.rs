@@ -0,0 +1,124 @@
+use std::collections::BTreeMap;
+
+use r2d2::Pool;
+
+use redis::Client;
+
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::{get, State};
+
+use super::auth::AdminAccess;
+use super::config::Config;
+use super::error::RegistryError;
+use super::manifest::metadata::{manifest_metadata, ManifestMetadata};
+use super::manifest::retention::{expired_tags, ExpiredTag};
+use super::manifest::{pushed_times, repository_keys, validate_name};
+use super::tags::is_accepted_digest;
+
+/// Raw redis keys stored for a repository
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct RepositoryKeys {
+    /// The repository name
+    pub name: String,
+    /// Type of each key, sorted by key
+    pub keys: BTreeMap<String, String>,
+}
+
+/// Inspect the raw redis keys of a repository, with their types, to debug
+/// inconsistencies between manifests, tags and aliases:
+/// - `name`: The repository name
+///
+/// It's read-only and restricted to the users listed in `ADMIN_USERS`.
+#[get("/repo/<name>/keys")]
+pub async fn keys(
+    name: &str,
+    connection_pool: &State<Pool<Client>>,
+    _access: AdminAccess,
+) -> Result<Json<RepositoryKeys>, RegistryError> {
+    validate_name(name)?;
+    let mut con = connection_pool.get()?;
+    let keys =
+        repository_keys(name, &mut con).map_err(|err| RegistryError::Unknown(err.to_string()))?;
+    Ok(Json(RepositoryKeys {
+        name: name.to_string(),
+        keys,
+    }))
+}
+
+/// When the tags and the manifests of a repository were last pushed, in
+/// seconds since the epoch
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct RepositoryPushes {
+    /// The repository name
+    pub name: String,
+    /// Push time of each tag, sorted by tag
+    pub tags: BTreeMap<String, u64>,
+    /// Push time of each manifest, sorted by digest
+    pub manifests: BTreeMap<String, u64>,
+}
+
+/// Read when the tags and the manifests of a repository were last pushed:
+/// - `name`: The repository name
+///
+/// It's read-only and restricted to the users listed in `ADMIN_USERS`.
+#[get("/repo/<name>/pushed")]
+pub async fn pushed(
+    name: &str,
+    connection_pool: &State<Pool<Client>>,
+    _access: AdminAccess,
+) -> Result<Json<RepositoryPushes>, RegistryError> {
+    validate_name(name)?;
+    let mut con = connection_pool.get()?;
+    let times =
+        pushed_times(name, &mut con).map_err(|err| RegistryError::Unknown(err.to_string()))?;
+    let (manifests, tags) = times
+        .into_iter()
+        .partition(|(reference, _)| is_accepted_digest(reference));
+    Ok(Json(RepositoryPushes {
+        name: name.to_string(),
+        tags,
+        manifests,
+    }))
+}
+
+/// Read the metadata of a stored manifest without deserializing it, when it
+/// was indexed with `MANIFEST_METADATA_HASH`:
+/// - `name`: The repository name
+/// - `digest`: The manifest digest
+///
+/// Manifests pushed without the index are deserialized instead.
+#[get("/repo/<name>/manifests/<digest>")]
+pub async fn metadata(
+    name: &str,
+    digest: &str,
+    connection_pool: &State<Pool<Client>>,
+    _access: AdminAccess,
+) -> Result<Option<Json<ManifestMetadata>>, RegistryError> {
+    validate_name(name)?;
+    if !is_accepted_digest(digest) {
+        return Ok(None);
+    }
+    let mut con = connection_pool.get()?;
+    manifest_metadata(name, digest, &mut con)
+        .map(|metadata| metadata.map(Json))
+        .map_err(|err| RegistryError::Unknown(err.to_string()))
+}
+
+/// List the tags the `TAG_RETENTION` rules would remove, a dry run of the
+/// next sweep.
+///
+/// It's read-only and restricted to the users listed in `ADMIN_USERS`.
+#[get("/retention")]
+pub async fn retention(
+    config: &State<Config>,
+    connection_pool: &State<Pool<Client>>,
+    _access: AdminAccess,
+) -> Result<Json<Vec<ExpiredTag>>, RegistryError> {
+    let mut con = connection_pool.get()?;
+    expired_tags(config, &mut con)
+        .map(Json)
+        .map_err(|err| RegistryError::Unknown(err.to_string()))
+}
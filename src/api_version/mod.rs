@@ -0,0 +1,30 @@
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Request, Response};
+
+/// Header Docker clients check to confirm the registry speaks the
+/// distribution API
+const API_VERSION_HEADER: &str = "Docker-Distribution-Api-Version";
+/// Version of the distribution API implemented
+const API_VERSION: &str = "registry/2.0";
+/// Prefix of the routes of the distribution API
+const API_ROUTES_PREFIX: &str = "/v2";
+
+/// Fairing adding `Docker-Distribution-Api-Version: registry/2.0` to every
+/// `/v2` response, errors and authentication challenges included
+pub struct ApiVersion;
+
+#[rocket::async_trait]
+impl Fairing for ApiVersion {
+    fn info(&self) -> Info {
+        Info {
+            name: "Distribution API version",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        if request.uri().path().starts_with(API_ROUTES_PREFIX) {
+            response.set_raw_header(API_VERSION_HEADER, API_VERSION);
+        }
+    }
+}
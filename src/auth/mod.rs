@@ -0,0 +1,374 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::serde::json::{serde_json, Json};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{catch, get, Request, State};
+
+use super::config::Config;
+use super::error::RegistryError;
+use super::manifest::is_manifest_name_valid;
+use super::tags::sha256_digest;
+
+/// Issuer of the tokens signed by the registry
+pub const TOKEN_ISSUER: &str = "rregistry";
+/// Service the registry accepts tokens for, advertised in the
+/// `WWW-Authenticate` challenge and checked against their audience
+pub const TOKEN_SERVICE: &str = "rregistry";
+/// How long, in seconds, an issued token is valid
+pub const TOKEN_LIFETIME: u64 = 300;
+/// Actions a token may grant on a repository
+const REPOSITORY_ACTIONS: [&str; 3] = ["pull", "push", "delete"];
+
+/// Credentials sent through the `Authorization: Basic` header
+pub struct BasicCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for BasicCredentials {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let encoded = match request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|value| value.strip_prefix("Basic "))
+        {
+            Some(encoded) => encoded,
+            None => return Outcome::Forward(()),
+        };
+        let decoded = base64::decode(encoded.trim())
+            .ok()
+            .and_then(|decoded| String::from_utf8(decoded).ok());
+        match decoded
+            .as_deref()
+            .and_then(|decoded| decoded.split_once(':'))
+        {
+            Some((username, password)) => Outcome::Success(BasicCredentials {
+                username: username.to_string(),
+                password: password.to_string(),
+            }),
+            None => Outcome::Failure((Status::BadRequest, ())),
+        }
+    }
+}
+
+impl BasicCredentials {
+    /// Check the password against the `sha256` digest configured for the
+    /// user in `AUTH_USERS`
+    pub fn is_valid(&self, config: &Config) -> bool {
+        config
+            .auth_users
+            .get(&self.username)
+            .is_some_and(|digest| *digest == sha256_digest(self.password.as_bytes()))
+    }
+}
+
+/// Access a request was refused for lack of valid credentials, sent back as
+/// the scope of the `WWW-Authenticate` challenge so clients know which
+/// token to ask for
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequiredAccess {
+    pub name: String,
+    pub action: &'static str,
+}
+
+impl RequiredAccess {
+    /// The access recorded for the request, if it was refused one
+    pub fn of<'r>(request: &'r Request<'_>) -> Option<&'r RequiredAccess> {
+        request.local_cache(|| None::<RequiredAccess>).as_ref()
+    }
+
+    /// Scope of a token granting the access, e.g.
+    /// `repository:library/ubuntu:pull`
+    pub fn scope(&self) -> String {
+        format!("repository:{}:{}", self.name, self.action)
+    }
+}
+
+/// Guard letting a request pull from the repository named by the route,
+/// anonymously when `ANONYMOUS_PULL` is enabled
+pub struct PullAccess;
+
+/// Guard letting a request push to the repository named by the route
+pub struct PushAccess;
+
+/// Guard letting a request delete from the repository named by the route
+pub struct DeleteAccess;
+
+/// Guard letting a user listed in `ADMIN_USERS` call the admin endpoints,
+/// with Basic credentials only
+pub struct AdminAccess;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for PullAccess {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        authorize(request, "pull").await.map(|()| PullAccess)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for PushAccess {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        authorize(request, "push").await.map(|()| PushAccess)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for DeleteAccess {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        authorize(request, "delete").await.map(|()| DeleteAccess)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminAccess {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let config = match request.rocket().state::<Config>() {
+            Some(config) => config,
+            None => return Outcome::Failure((Status::InternalServerError, ())),
+        };
+        match request.guard::<BasicCredentials>().await {
+            Outcome::Success(credentials) if credentials.is_valid(config) => {
+                if config.admin_users.contains(&credentials.username) {
+                    Outcome::Success(AdminAccess)
+                } else {
+                    Outcome::Failure((Status::Forbidden, ()))
+                }
+            }
+            _ => Outcome::Failure((Status::Unauthorized, ())),
+        }
+    }
+}
+
+/// Check a request may run `action` on the repository named by the first
+/// route parameter, either with a token granting it or with valid Basic
+/// credentials. Without a token key everything is allowed.
+///
+/// Missing or invalid credentials fail with `401`, while a valid token not
+/// granting the action fails with `403`.
+async fn authorize(request: &Request<'_>, action: &'static str) -> Outcome<(), ()> {
+    let config = match request.rocket().state::<Config>() {
+        Some(config) => config,
+        None => return Outcome::Failure((Status::InternalServerError, ())),
+    };
+    let key = match &config.auth_token_key {
+        Some(key) => key,
+        None => return Outcome::Success(()),
+    };
+    if action == "pull" && config.anonymous_pull {
+        return Outcome::Success(());
+    }
+    let name = request.param::<&str>(0).and_then(Result::ok);
+    let unauthorized = || {
+        if let Some(name) = name {
+            request.local_cache(|| {
+                Some(RequiredAccess {
+                    name: name.to_string(),
+                    action,
+                })
+            });
+        }
+        Outcome::Failure((Status::Unauthorized, ()))
+    };
+    let authorization = request.headers().get_one("Authorization");
+    if let Some(token) = authorization.and_then(|value| value.strip_prefix("Bearer ")) {
+        return match (verify(token.trim(), key), name) {
+            (Some(claims), Some(name)) if claims.grants(name, action) => Outcome::Success(()),
+            (Some(_), _) => Outcome::Failure((Status::Forbidden, ())),
+            (None, _) => unauthorized(),
+        };
+    }
+    match request.guard::<BasicCredentials>().await {
+        Outcome::Success(credentials) if credentials.is_valid(config) => Outcome::Success(()),
+        _ => unauthorized(),
+    }
+}
+
+/// Answer requests failing authentication with the OCI error body and the
+/// credentials challenge
+#[catch(401)]
+pub fn unauthorized() -> RegistryError {
+    RegistryError::Unauthorized("valid credentials or token required".to_string())
+}
+
+/// Answer requests lacking the access they need with the OCI error body
+#[catch(403)]
+pub fn denied() -> RegistryError {
+    RegistryError::Denied("credentials don't grant the requested access".to_string())
+}
+
+/// Answer requests whose body exceeds its limit, e.g. a JSON body bigger
+/// than `MAX_REQUEST_BODY`, with the OCI error body
+#[catch(413)]
+pub fn too_large() -> RegistryError {
+    RegistryError::TooLarge("request body is bigger than allowed".to_string())
+}
+
+/// Access granted by a token on a single resource, e.g. `pull` and `push`
+/// on the repository `library/ubuntu`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct Access {
+    #[serde(rename = "type")]
+    pub resource_type: String,
+    pub name: String,
+    pub actions: Vec<String>,
+}
+
+/// Claims of a token signed by the registry
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct TokenClaims {
+    pub iss: String,
+    pub sub: String,
+    pub aud: String,
+    pub iat: u64,
+    pub exp: u64,
+    pub access: Vec<Access>,
+}
+
+impl TokenClaims {
+    /// Check the claims grant an action on a repository
+    pub fn grants(&self, name: &str, action: &str) -> bool {
+        self.access.iter().any(|access| {
+            access.resource_type == "repository"
+                && access.name == name
+                && access.actions.iter().any(|granted| granted == action)
+        })
+    }
+}
+
+/// Response of the token endpoint, `access_token` is kept for OAuth2 clients
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct TokenResponse {
+    pub token: String,
+    pub access_token: String,
+    pub expires_in: u64,
+}
+
+/// Issue a token granting the requested scopes, once the Basic credentials
+/// are validated:
+/// - `service`: The service the token is intended for, e.g. `rregistry`
+/// - `scope`: Requested scopes, e.g. `repository:library/ubuntu:pull,push`
+///
+/// Unknown resource types, invalid repository names and unknown actions
+/// are left out of the token instead of failing the request.
+#[get("/token?<service>&<scope>")]
+pub async fn token(
+    service: Option<String>,
+    scope: Vec<String>,
+    credentials: Option<BasicCredentials>,
+    config: &State<Config>,
+) -> Result<Json<TokenResponse>, RegistryError> {
+    let key = match &config.auth_token_key {
+        Some(key) => key,
+        None => {
+            return Err(RegistryError::Unauthorized(
+                "token authentication isn't configured".to_string(),
+            ))
+        }
+    };
+    let credentials = match credentials {
+        Some(credentials) if credentials.is_valid(config) => credentials,
+        _ => {
+            return Err(RegistryError::Unauthorized(
+                "invalid username or password".to_string(),
+            ))
+        }
+    };
+    let issued_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time after unix epoch")
+        .as_secs();
+    let claims = TokenClaims {
+        iss: TOKEN_ISSUER.to_string(),
+        sub: credentials.username,
+        aud: service.unwrap_or_else(|| TOKEN_SERVICE.to_string()),
+        iat: issued_at,
+        exp: issued_at + TOKEN_LIFETIME,
+        access: scope
+            .iter()
+            .filter_map(|scope| parse_scope(scope))
+            .collect(),
+    };
+    let token = sign(&claims, key);
+    Ok(Json(TokenResponse {
+        access_token: token.clone(),
+        token,
+        expires_in: TOKEN_LIFETIME,
+    }))
+}
+
+/// Parse a scope like `repository:library/ubuntu:pull,push`, keeping only
+/// the known actions
+pub fn parse_scope(scope: &str) -> Option<Access> {
+    let (resource_type, rest) = scope.split_once(':')?;
+    let (name, actions) = rest.rsplit_once(':')?;
+    if resource_type != "repository" || !is_manifest_name_valid(name) {
+        return None;
+    }
+    let actions = actions
+        .split(',')
+        .filter(|action| REPOSITORY_ACTIONS.contains(action))
+        .map(str::to_string)
+        .collect();
+    Some(Access {
+        resource_type: resource_type.to_string(),
+        name: name.to_string(),
+        actions,
+    })
+}
+
+/// Sign the claims as a JWT using `HS256` with the configured key
+pub fn sign(claims: &TokenClaims, key: &str) -> String {
+    let header = base64_url(br#"{"alg":"HS256","typ":"JWT"}"#);
+    let payload = base64_url(&serde_json::to_vec(claims).expect("serializable claims"));
+    let signing_input = format!("{}.{}", header, payload);
+    let mut mac = Hmac::<Sha256>::new_varkey(key.as_bytes()).expect("any key length");
+    mac.update(signing_input.as_bytes());
+    let signature = base64_url(&mac.finalize().into_bytes());
+    format!("{}.{}", signing_input, signature)
+}
+
+/// Verify a token signed by [`sign`] with the configured key, returning its
+/// claims unless the signature doesn't match, the token expired or it was
+/// issued for another service than [`TOKEN_SERVICE`]
+pub fn verify(token: &str, key: &str) -> Option<TokenClaims> {
+    let (signing_input, signature) = token.rsplit_once('.')?;
+    let (_, payload) = signing_input.split_once('.')?;
+    let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).ok()?;
+    let mut mac = Hmac::<Sha256>::new_varkey(key.as_bytes()).expect("any key length");
+    mac.update(signing_input.as_bytes());
+    mac.verify(&signature).ok()?;
+    let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+    let claims: TokenClaims = serde_json::from_slice(&payload).ok()?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time after unix epoch")
+        .as_secs();
+    if claims.iss != TOKEN_ISSUER || claims.aud != TOKEN_SERVICE || claims.exp <= now {
+        return None;
+    }
+    Some(claims)
+}
+
+#[doc(hidden)]
+fn base64_url(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
@@ -1,8 +1,107 @@
+use rocket::serde::json::Json;
 use rocket::serde::{Deserialize, Serialize};
+use rocket::{post, State};
 
+use tracing::{field, instrument};
+
+use super::auth::PushAccess;
+use super::config::Config;
+use super::error::RegistryError;
+use super::manifest::validate_name;
+use super::storage::{Filesystem, StorageBackend};
+use super::tags::{content_digest, normalize_digest, sha256_digest};
+use super::telemetry::TraceParent;
+
+/// Digest of the [empty descriptor](https://github.com/opencontainers/image-spec/blob/main/manifest.md#guidance-for-an-empty-descriptor)
+/// content, referenced as config by OCI artifacts with the
+/// `application/vnd.oci.empty.v1+json` media type
+pub const EMPTY_BLOB_DIGEST: &str =
+    "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a";
+/// Content of the empty descriptor
+pub const EMPTY_BLOB_CONTENT: &[u8] = b"{}";
+
+#[allow(dead_code)]
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(crate = "rocket::serde")]
 pub struct Blob {
     pub digest: String,
     pub bytes: Vec<u8>,
 }
+
+#[allow(dead_code)]
+impl Blob {
+    /// Creates a blob from its content, computing its `sha256` digest
+    pub fn from_bytes(bytes: Vec<u8>) -> Blob {
+        Blob {
+            digest: sha256_digest(&bytes),
+            bytes,
+        }
+    }
+
+    /// The well known empty blob, `{}`, which is never uploaded
+    pub fn empty() -> Blob {
+        Blob {
+            digest: EMPTY_BLOB_DIGEST.to_string(),
+            bytes: EMPTY_BLOB_CONTENT.to_vec(),
+        }
+    }
+
+    /// Check the content matches the digest, hashing it with the digest's
+    /// algorithm. Digests of unsupported algorithms never match.
+    pub fn verify(&self) -> bool {
+        normalize_digest(&self.digest)
+            .and_then(|digest| {
+                let (algorithm, _) = digest.split_once(':')?;
+                content_digest(algorithm, &self.bytes).map(|computed| computed == digest)
+            })
+            .unwrap_or(false)
+    }
+}
+
+/// Which of the probed blobs are already stored, in the requested order
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct BlobPresence {
+    pub present: Vec<String>,
+    pub missing: Vec<String>,
+}
+
+/// Check which blobs are already stored, given a JSON array of digests:
+/// - `name`: The repository name
+///
+/// This endpoint isn't part of the OCI Distribution specification, it lets
+/// pushers probe every layer at once instead of sending a `HEAD` per blob.
+/// Blobs are shared by every repository, so it needs push access like the
+/// uploads it saves.
+#[post("/<name>/blobs/exists", data = "<digests>")]
+#[instrument(name = "blobs_exist", skip_all, fields(repository = %name, request_id = field::Empty))]
+pub async fn blobs_exist(
+    name: &str,
+    digests: Json<Vec<String>>,
+    config: &State<Config>,
+    _access: PushAccess,
+    trace_parent: TraceParent,
+) -> Result<Json<BlobPresence>, RegistryError> {
+    trace_parent.adopt();
+    validate_name(name)?;
+    let storage = Filesystem::from_config(config);
+    let mut presence = BlobPresence {
+        present: vec![],
+        missing: vec![],
+    };
+    for digest in digests.into_inner() {
+        let digest = normalize_digest(&digest).ok_or_else(|| {
+            RegistryError::DigestInvalid(format!("malformed digest `{}`", digest))
+        })?;
+        if digest == EMPTY_BLOB_DIGEST
+            || storage
+                .as_ref()
+                .is_some_and(|storage| storage.exists(&digest))
+        {
+            presence.present.push(digest);
+        } else {
+            presence.missing.push(digest);
+        }
+    }
+    Ok(Json(presence))
+}
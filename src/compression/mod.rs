@@ -0,0 +1,86 @@
+use std::io::{Cursor, Write};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Request, Response};
+
+/// Bodies smaller than this, in bytes, aren't worth compressing
+pub const GZIP_MIN_SIZE: usize = 1024;
+
+/// Check if the client accepts a gzip encoded response through the
+/// `Accept-Encoding` header, e.g. `Accept-Encoding: gzip, deflate`
+pub fn accepts_gzip(request: &Request<'_>) -> bool {
+    request
+        .headers()
+        .get("Accept-Encoding")
+        .flat_map(|value| value.split(','))
+        .any(|encoding| {
+            let mut parameters = encoding.split(';').map(str::trim);
+            let coding = parameters.next().unwrap_or_default();
+            let refused = parameters.any(|parameter| {
+                parameter
+                    .strip_prefix("q=")
+                    .and_then(|quality| quality.parse::<f32>().ok())
+                    == Some(0.0)
+            });
+            (coding.eq_ignore_ascii_case("gzip") || coding == "*") && !refused
+        })
+}
+
+/// Check if a body should be gzip compressed for the given request
+pub fn should_gzip(request: &Request<'_>, body: &[u8]) -> bool {
+    body.len() >= GZIP_MIN_SIZE && accepts_gzip(request)
+}
+
+/// Compress a body using gzip
+pub fn gzip(body: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body)?;
+    encoder.finish()
+}
+
+/// Check if a response content type is JSON, including the `+json` media
+/// types manifests and indexes are served with
+fn is_json(response: &Response<'_>) -> bool {
+    response.content_type().is_some_and(|content_type| {
+        content_type.is_json() || content_type.media_type().sub().as_str().ends_with("+json")
+    })
+}
+
+/// Fairing gzip encoding the JSON responses big enough, when the client
+/// accepts it. Blobs are left alone, layers are compressed already.
+pub struct Gzip;
+
+#[rocket::async_trait]
+impl Fairing for Gzip {
+    fn info(&self) -> Info {
+        Info {
+            name: "Gzip",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        if !is_json(response) || response.headers().contains("Content-Encoding") {
+            return;
+        }
+        response.adjoin_raw_header("Vary", "Accept-Encoding");
+        if !accepts_gzip(request) {
+            return;
+        }
+        let body = match response.body_mut().to_bytes().await {
+            Ok(body) => body,
+            Err(_) => return,
+        };
+        let body = match should_gzip(request, &body).then(|| gzip(&body)) {
+            Some(Ok(compressed)) => {
+                response.set_raw_header("Content-Encoding", "gzip");
+                compressed
+            }
+            _ => body,
+        };
+        response.set_sized_body(body.len(), Cursor::new(body));
+    }
+}
@@ -0,0 +1,106 @@
+use std::io::Cursor;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Method, Status};
+use rocket::{Request, Response};
+
+use super::config::Config;
+
+/// Methods browsers may use on the registry routes
+const ALLOWED_METHODS: &str = "GET, HEAD, PUT, PATCH, POST, DELETE, OPTIONS";
+/// Request headers browsers may send, unless the preflight asks for others
+const ALLOWED_HEADERS: &str = "Accept, Accept-Encoding, Authorization, Content-Type";
+/// Response headers browsers let UIs read, besides the CORS-safelisted ones
+const EXPOSED_HEADERS: &str =
+    "Docker-Content-Digest, Docker-Distribution-Api-Version, ETag, Link, \
+    Location, Warning, X-Request-Id";
+/// Prefix of the routes CORS applies to
+const CORS_ROUTES_PREFIX: &str = "/v2";
+
+/// Fairing adding CORS headers to the `/v2` routes for the origins allowed
+/// by `CORS_ALLOWED_ORIGINS`, and answering their `OPTIONS` preflight
+/// requests. Preflights from other origins, or for paths no route answers,
+/// are left unanswered.
+///
+/// Without allowed origins it does nothing.
+pub struct Cors {
+    allowed_origins: Vec<String>,
+}
+
+impl Cors {
+    /// Creates the fairing with the configured allowed origins
+    pub fn new(config: &Config) -> Cors {
+        Cors {
+            allowed_origins: config.cors_allowed_origins.clone(),
+        }
+    }
+
+    /// Check if an origin is allowed, `*` allows every origin
+    fn is_allowed(&self, origin: &str) -> bool {
+        self.allowed_origins
+            .iter()
+            .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin))
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for Cors {
+    fn info(&self) -> Info {
+        Info {
+            name: "CORS",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        if self.allowed_origins.is_empty() || !request.uri().path().starts_with(CORS_ROUTES_PREFIX)
+        {
+            return;
+        }
+        // responses differ by origin, even those without CORS headers
+        response.adjoin_raw_header("Vary", "Origin");
+        let origin = match request.headers().get_one("Origin") {
+            Some(origin) if self.is_allowed(origin) => origin.to_string(),
+            _ => return,
+        };
+        let preflight = request.method() == Method::Options;
+        if preflight {
+            if !has_route(request) {
+                return;
+            }
+            response.set_status(Status::NoContent);
+            response.remove_header("Content-Type");
+            response.set_sized_body(0, Cursor::new(""));
+        }
+        response.set_raw_header("Access-Control-Allow-Origin", origin);
+        if preflight {
+            let headers = request
+                .headers()
+                .get_one("Access-Control-Request-Headers")
+                .unwrap_or(ALLOWED_HEADERS)
+                .to_string();
+            response.set_raw_header("Access-Control-Allow-Methods", ALLOWED_METHODS);
+            response.set_raw_header("Access-Control-Allow-Headers", headers);
+        } else {
+            response.set_raw_header("Access-Control-Expose-Headers", EXPOSED_HEADERS);
+        }
+    }
+}
+
+/// Check if a route answers the path of a preflight request, whatever its
+/// method, dynamic segments matching any segment
+fn has_route(request: &Request<'_>) -> bool {
+    let path: Vec<&str> = request.uri().path().segments().collect();
+    request.rocket().routes().any(|route| {
+        let segments: Vec<&str> = route
+            .uri
+            .path()
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect();
+        segments.len() == path.len()
+            && segments.iter().zip(&path).all(|(segment, requested)| {
+                (segment.starts_with('<') && segment.ends_with('>')) || segment == requested
+            })
+    })
+}
@@ -0,0 +1,229 @@
+use rocket::http::Status;
+use rocket::response::{self, Responder};
+use rocket::serde::json::{serde_json, Json, Value};
+use rocket::serde::Serialize;
+use rocket::Request;
+
+use super::auth::{RequiredAccess, TOKEN_ISSUER, TOKEN_SERVICE};
+use super::config::Config;
+use super::manifest::validation::ValidationProblem;
+use super::proxy::ExternalOrigin;
+use super::request_id::RequestId;
+
+/// Seconds clients are asked to wait before retrying when the registry is
+/// temporarily unavailable
+pub const RETRY_AFTER_SECONDS: u64 = 5;
+
+/// Errors returned to clients using the
+/// [OCI error format](https://github.com/opencontainers/distribution-spec/blob/main/spec.md#error-codes)
+///
+/// Variants are named after the OCI error codes, or the docker distribution
+/// ones the OCI specification doesn't define.
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegistryError {
+    /// Blob unknown to registry
+    BlobUnknown(String),
+    /// Provided digest did not match uploaded content
+    DigestInvalid(String),
+    /// Manifest invalid
+    ManifestInvalid(String),
+    /// Manifest invalid, with every problem found validating it
+    ManifestProblems(Vec<ValidationProblem>),
+    /// Invalid repository name
+    NameInvalid(String),
+    /// Manifest tag did not match URI
+    TagInvalid(String),
+    /// Authentication required
+    Unauthorized(String),
+    /// Requested access to the resource is denied
+    Denied(String),
+    /// Unknown error, e.g. the storage couldn't be reached
+    Unknown(String),
+    /// Service unavailable, e.g. every redis connection is busy
+    Unavailable(String),
+    /// Request body bigger than the configured limit, denied as the OCI
+    /// `SIZE_INVALID` code is for content not matching its declared length
+    TooLarge(String),
+}
+
+/// Body of an OCI error response
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ErrorResponse {
+    pub errors: Vec<ErrorInfo>,
+}
+
+/// A single error of an OCI error response
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ErrorInfo {
+    pub code: &'static str,
+    pub message: String,
+    /// The id of the request, with either the reason of the error or the
+    /// problems of an invalid manifest
+    pub detail: Value,
+}
+
+impl RegistryError {
+    /// The OCI error code
+    pub fn code(&self) -> &'static str {
+        match self {
+            RegistryError::BlobUnknown(_) => "BLOB_UNKNOWN",
+            RegistryError::DigestInvalid(_) => "DIGEST_INVALID",
+            RegistryError::ManifestInvalid(_) | RegistryError::ManifestProblems(_) => {
+                "MANIFEST_INVALID"
+            }
+            RegistryError::NameInvalid(_) => "NAME_INVALID",
+            RegistryError::TagInvalid(_) => "TAG_INVALID",
+            RegistryError::Unauthorized(_) => "UNAUTHORIZED",
+            RegistryError::Denied(_) => "DENIED",
+            RegistryError::Unknown(_) => "UNKNOWN",
+            RegistryError::Unavailable(_) => "UNAVAILABLE",
+            RegistryError::TooLarge(_) => "DENIED",
+        }
+    }
+
+    /// The HTTP status returned for this error
+    pub fn status(&self) -> Status {
+        match self {
+            RegistryError::BlobUnknown(_) => Status::NotFound,
+            RegistryError::DigestInvalid(_)
+            | RegistryError::ManifestInvalid(_)
+            | RegistryError::ManifestProblems(_)
+            | RegistryError::NameInvalid(_)
+            | RegistryError::TagInvalid(_) => Status::BadRequest,
+            RegistryError::Unauthorized(_) => Status::Unauthorized,
+            RegistryError::Denied(_) => Status::Forbidden,
+            RegistryError::Unknown(_) => Status::InternalServerError,
+            RegistryError::Unavailable(_) => Status::ServiceUnavailable,
+            RegistryError::TooLarge(_) => Status::PayloadTooLarge,
+        }
+    }
+
+    /// Generic message describing the error code
+    pub fn message(&self) -> &'static str {
+        match self {
+            RegistryError::BlobUnknown(_) => "blob unknown to registry",
+            RegistryError::DigestInvalid(_) => "provided digest did not match uploaded content",
+            RegistryError::ManifestInvalid(_) | RegistryError::ManifestProblems(_) => {
+                "manifest invalid"
+            }
+            RegistryError::NameInvalid(_) => "invalid repository name",
+            RegistryError::TagInvalid(_) => "manifest tag did not match URI",
+            RegistryError::Unauthorized(_) => "authentication required",
+            RegistryError::Denied(_) => "requested access to the resource is denied",
+            RegistryError::Unknown(_) => "unknown error",
+            RegistryError::Unavailable(_) => "service unavailable",
+            RegistryError::TooLarge(_) => "request body exceeds the size limit",
+        }
+    }
+
+    /// Details about this specific occurrence of the error, each problem of
+    /// an invalid manifest as `field: message`
+    pub fn detail(&self) -> String {
+        match self {
+            RegistryError::ManifestProblems(problems) => problems
+                .iter()
+                .map(|problem| format!("{}: {}", problem.field, problem.message))
+                .collect::<Vec<_>>()
+                .join("; "),
+            RegistryError::BlobUnknown(detail)
+            | RegistryError::DigestInvalid(detail)
+            | RegistryError::ManifestInvalid(detail)
+            | RegistryError::NameInvalid(detail)
+            | RegistryError::TagInvalid(detail)
+            | RegistryError::Unauthorized(detail)
+            | RegistryError::Denied(detail)
+            | RegistryError::Unknown(detail)
+            | RegistryError::Unavailable(detail)
+            | RegistryError::TooLarge(detail) => detail.clone(),
+        }
+    }
+
+    /// Details sent in the error body, along with the id of the request to
+    /// find it in the logs. The problems of an invalid manifest are listed
+    /// with their fields so clients can point at each of them.
+    fn detail_value(&self, request_id: Option<&str>) -> Value {
+        let (name, detail) = match self {
+            RegistryError::ManifestProblems(problems) => (
+                "problems",
+                serde_json::to_value(problems).unwrap_or_else(|_| Value::from(self.detail())),
+            ),
+            _ => ("reason", Value::from(self.detail())),
+        };
+        match request_id {
+            Some(request_id) => serde_json::json!({ "requestId": request_id, name: detail }),
+            None => detail,
+        }
+    }
+}
+
+/// No redis connection was handed out by the pool in time
+impl From<r2d2::Error> for RegistryError {
+    fn from(err: r2d2::Error) -> Self {
+        log::warn!("couldn't get a redis connection: {}", err);
+        RegistryError::Unavailable("no redis connection available".to_string())
+    }
+}
+
+/// Respond with the error status and the OCI error body, challenging the
+/// client for credentials when authentication is required and asking it to
+/// back off when the registry is unavailable
+impl<'r> Responder<'r, 'static> for RegistryError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let challenge = match self {
+            RegistryError::Unauthorized(_) => Some(challenge(request)),
+            _ => None,
+        };
+        let unavailable = matches!(self, RegistryError::Unavailable(_));
+        let status = self.status();
+        let request_id = RequestId::of(request);
+        if let Some(request_id) = request_id {
+            log::info!(
+                "request_id={} error={} detail={}",
+                request_id,
+                self.code(),
+                self.detail()
+            );
+        }
+        let body = ErrorResponse {
+            errors: vec![ErrorInfo {
+                code: self.code(),
+                message: self.message().to_string(),
+                detail: self.detail_value(request_id),
+            }],
+        };
+        let mut response = (status, Json(body)).respond_to(request)?;
+        if let Some(challenge) = challenge {
+            response.set_raw_header("WWW-Authenticate", challenge);
+        }
+        if unavailable {
+            response.set_raw_header("Retry-After", RETRY_AFTER_SECONDS.to_string());
+        }
+        Ok(response)
+    }
+}
+
+/// Credentials challenge of a `401`. With token authentication, clients of
+/// the `/v2` routes are pointed at the token endpoint along with the scope
+/// they were refused, while the token endpoint itself and the admin routes
+/// take Basic credentials.
+fn challenge(request: &Request<'_>) -> String {
+    let token_auth = request
+        .rocket()
+        .state::<Config>()
+        .is_some_and(|config| config.auth_token_key.is_some());
+    if !token_auth || !request.uri().path().starts_with("/v2") {
+        return format!("Basic realm=\"{}\"", TOKEN_ISSUER);
+    }
+    let mut challenge = format!(
+        "Bearer realm=\"{}\",service=\"{}\"",
+        ExternalOrigin::or_host(request).url("/token"),
+        TOKEN_SERVICE
+    );
+    if let Some(access) = RequiredAccess::of(request) {
+        challenge.push_str(&format!(",scope=\"{}\"", access.scope()));
+    }
+    challenge
+}
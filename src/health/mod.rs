@@ -0,0 +1,65 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use r2d2::Pool;
+
+use redis::Client;
+
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::{get, State};
+
+use super::config::Config;
+use super::storage::Filesystem;
+
+/// How long to wait for a redis connection before reporting it unavailable
+const READINESS_TIMEOUT: Duration = Duration::from_secs(2);
+/// Result of a successful check
+const CHECK_OK: &str = "ok";
+
+/// Readiness of the registry, with the result of each dependency check:
+/// `ok`, or why the dependency isn't usable
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: BTreeMap<&'static str, String>,
+}
+
+/// Check the registry can serve requests: redis answers and, when it's
+/// configured, the storage path accepts writes. Answers `503` naming the
+/// failed dependencies otherwise.
+#[get("/readyz")]
+pub async fn readyz(
+    connection_pool: &State<Pool<Client>>,
+    config: &State<Config>,
+) -> (Status, Json<Readiness>) {
+    let mut checks = BTreeMap::new();
+    checks.insert("redis", outcome(ping(connection_pool)));
+    if let Some(storage) = Filesystem::from_config(config) {
+        checks.insert("storage", outcome(storage.check_writable()));
+    }
+    let ready = checks.values().all(|check| check == CHECK_OK);
+    let status = if ready {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    };
+    (status, Json(Readiness { ready, checks }))
+}
+
+/// Ping redis through the connection pool
+fn ping(connection_pool: &Pool<Client>) -> anyhow::Result<()> {
+    let mut con = connection_pool.get_timeout(READINESS_TIMEOUT)?;
+    redis::cmd("PING").query::<()>(&mut *con)?;
+    Ok(())
+}
+
+#[doc(hidden)]
+fn outcome<E: std::fmt::Display>(result: Result<(), E>) -> String {
+    match result {
+        Ok(()) => CHECK_OK.to_string(),
+        Err(err) => err.to_string(),
+    }
+}
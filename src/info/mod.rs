@@ -0,0 +1,48 @@
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::{get, State};
+
+use super::config::Config;
+use super::manifest::validation::ACCEPTED_MANIFEST_MEDIA_TYPES;
+use super::manifest::INDEX_MEDIA_TYPE;
+
+/// Version of the registry, from its crate
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Git commit the registry was built from, set by the build script
+const GIT_HASH: &str = env!("GIT_HASH");
+
+/// What the registry is and which capabilities are enabled
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde", rename_all = "camelCase")]
+pub struct RegistryInfo {
+    /// Version of the registry, e.g. `0.0.1`
+    pub version: &'static str,
+    /// Git commit the registry was built from, `unknown` outside a checkout
+    pub git_hash: &'static str,
+    /// How clients are authenticated
+    pub auth: &'static str,
+    /// Where blobs are stored
+    pub storage: &'static str,
+    /// Optional features enabled through the environment
+    pub features: Vec<&'static str>,
+    /// Media types of the manifests which can be pushed
+    pub manifest_media_types: Vec<&'static str>,
+}
+
+/// Describe the registry so operators and client tooling can discover its
+/// capabilities. It's neither authenticated nor does it reach redis.
+#[get("/_info")]
+pub async fn info(config: &State<Config>) -> Json<RegistryInfo> {
+    Json(RegistryInfo {
+        version: VERSION,
+        git_hash: GIT_HASH,
+        auth: config.auth_mode(),
+        storage: config.storage_backend(),
+        features: config.features(),
+        manifest_media_types: ACCEPTED_MANIFEST_MEDIA_TYPES
+            .iter()
+            .copied()
+            .chain(std::iter::once(INDEX_MEDIA_TYPE))
+            .collect(),
+    })
+}
@@ -0,0 +1,85 @@
+use super::{Manifest, MANIFEST_PREFIX_KEY};
+use crate::storage::StorageBackend;
+use crate::tags::is_accepted_digest;
+
+use anyhow::Result;
+
+use redis::{Commands, Connection};
+
+use rocket::serde::json::serde_json;
+use rocket::serde::Serialize;
+
+use std::collections::BTreeSet;
+
+/// Blobs removed along with a deleted manifest
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(crate = "rocket::serde", rename_all = "camelCase")]
+pub struct CascadeSummary {
+    /// Digest of the deleted manifest
+    pub manifest: String,
+    /// Blobs no other manifest referenced, removed from the storage
+    pub removed_blobs: Vec<String>,
+    /// Blobs still referenced by another manifest, kept
+    pub kept_blobs: Vec<String>,
+}
+
+/// Digests of the config and the layers of a manifest
+pub fn blob_digests(manifest: &Manifest) -> BTreeSet<String> {
+    std::iter::once(&manifest.config)
+        .chain(&manifest.layers)
+        .map(|descriptor| descriptor.digest.clone())
+        .collect()
+}
+
+/// Digests of the blobs referenced by every stored manifest, whatever the
+/// repository, as blobs are shared by all of them
+pub fn referenced_blobs(con: &mut Connection) -> Result<BTreeSet<String>> {
+    let pattern = format!("{}::*", MANIFEST_PREFIX_KEY);
+    let keys: Vec<String> = con.scan_match(&pattern)?.collect();
+    let mut referenced = BTreeSet::new();
+    for key in keys {
+        let is_manifest = key
+            .strip_prefix(MANIFEST_PREFIX_KEY)
+            .and_then(|key| key.strip_prefix("::"))
+            .and_then(|key| key.split_once("::"))
+            .is_some_and(|(_, rest)| is_accepted_digest(rest));
+        if !is_manifest {
+            continue;
+        }
+        let content: Option<Vec<u8>> = con.get(&key)?;
+        if let Some(manifest) = content
+            .as_deref()
+            .and_then(|content| serde_json::from_slice::<Manifest>(content).ok())
+        {
+            referenced.extend(blob_digests(&manifest));
+        }
+    }
+    Ok(referenced)
+}
+
+/// Remove the blobs of a deleted manifest which no stored manifest
+/// references anymore.
+///
+/// A manifest pushed while it runs, referencing one of the blobs, may be
+/// left without it.
+pub fn remove_unreferenced(
+    digest: &str,
+    blobs: BTreeSet<String>,
+    storage: &dyn StorageBackend,
+    con: &mut Connection,
+) -> Result<CascadeSummary> {
+    let referenced = referenced_blobs(con)?;
+    let mut summary = CascadeSummary {
+        manifest: digest.to_string(),
+        removed_blobs: vec![],
+        kept_blobs: vec![],
+    };
+    for blob in blobs {
+        if referenced.contains(&blob) {
+            summary.kept_blobs.push(blob);
+        } else if storage.delete(&blob)? {
+            summary.removed_blobs.push(blob);
+        }
+    }
+    Ok(summary)
+}
@@ -0,0 +1,172 @@
+use super::{
+    generate_alias_key, generate_manifest_key, generate_tags_key, PushedManifest,
+    MANIFEST_ALIAS_SUFFIX_KEY, MANIFEST_PREFIX_KEY,
+};
+use crate::metrics::StorageUsage;
+use crate::storage::{Filesystem, StorageBackend, StorageError};
+use crate::tags::is_accepted_digest;
+
+use anyhow::Result;
+
+use r2d2::Pool;
+
+use redis::{Client, Commands, Connection};
+
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::ops::DerefMut;
+use std::time::Duration;
+
+/// Discrepancies between the manifests and the blobs found by a consistency
+/// check
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Findings {
+    /// Manifests sampled and checked
+    pub checked_manifests: usize,
+    /// Blobs referenced by a manifest but not stored, as the manifest key
+    /// and the blob digest
+    pub missing_blobs: Vec<(String, String)>,
+    /// Blobs whose stored size isn't the one declared by a manifest, as the
+    /// manifest key and the blob digest
+    pub size_mismatches: Vec<(String, String)>,
+    /// Alias sets of a manifest which isn't stored, or naming a tag which
+    /// doesn't point at it
+    pub orphaned_aliases: Vec<String>,
+}
+
+/// Check a sample of the stored manifests, `sample_rate` being the share of
+/// them checked, between `0` and `1`: every blob they reference must be
+/// stored with the declared size, and the tags of their alias set must point
+/// at them.
+///
+/// Each discrepancy is logged as it's found.
+pub fn check(
+    con: &mut Connection,
+    storage: &dyn StorageBackend,
+    sample_rate: f64,
+) -> Result<Findings> {
+    let pattern = format!("{}::*", MANIFEST_PREFIX_KEY);
+    let keys: Vec<String> = con.scan_match(&pattern)?.collect();
+    let alias_suffix = format!("::{}", MANIFEST_ALIAS_SUFFIX_KEY);
+    let sampling = RandomState::new();
+    let mut findings = Findings::default();
+    for key in &keys {
+        let (name, rest) = match key
+            .strip_prefix(MANIFEST_PREFIX_KEY)
+            .and_then(|key| key.strip_prefix("::"))
+            .and_then(|key| key.split_once("::"))
+        {
+            Some(parts) => parts,
+            None => continue,
+        };
+        let digest = rest.strip_suffix(&alias_suffix).unwrap_or(rest);
+        if !is_accepted_digest(digest)
+            || (sampling.hash_one(digest) as f64) >= sample_rate * u64::MAX as f64
+        {
+            continue;
+        }
+        if digest == rest {
+            check_blobs(key, storage, con, &mut findings)?;
+        } else if is_orphaned(name, digest, con)? {
+            log::warn!("consistency check: orphaned_alias key={}", key);
+            findings.orphaned_aliases.push(key.clone());
+        }
+    }
+    Ok(findings)
+}
+
+/// Check the blobs of a stored manifest, image indexes referencing no blob
+fn check_blobs(
+    key: &str,
+    storage: &dyn StorageBackend,
+    con: &mut Connection,
+    findings: &mut Findings,
+) -> Result<()> {
+    // deleted since it was scanned
+    let content: Vec<u8> = match con.get(key)? {
+        Some(content) => content,
+        None => return Ok(()),
+    };
+    findings.checked_manifests += 1;
+    let manifest = match PushedManifest::parse(&content) {
+        Ok(PushedManifest::Image(manifest)) => manifest,
+        _ => return Ok(()),
+    };
+    for descriptor in std::iter::once(&manifest.config).chain(&manifest.layers) {
+        match storage.size(&descriptor.digest) {
+            Ok(size) if size as i64 == descriptor.size => {}
+            Ok(size) => {
+                log::warn!(
+                    "consistency check: size_mismatch manifest={} blob={} declared={} stored={}",
+                    key,
+                    descriptor.digest,
+                    descriptor.size,
+                    size
+                );
+                findings
+                    .size_mismatches
+                    .push((key.to_string(), descriptor.digest.clone()));
+            }
+            // a malformed digest can't be stored either
+            Err(StorageError::NotFound(_)) | Err(StorageError::Backend(_)) => {
+                log::warn!(
+                    "consistency check: missing_blob manifest={} blob={}",
+                    key,
+                    descriptor.digest
+                );
+                findings
+                    .missing_blobs
+                    .push((key.to_string(), descriptor.digest.clone()));
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Check if the alias set of a digest outlived its manifest, or names a tag
+/// pointing at another digest
+fn is_orphaned(name: &str, digest: &str, con: &mut Connection) -> Result<bool> {
+    let stored: bool = con.exists(generate_manifest_key(name, digest))?;
+    if !stored {
+        return Ok(true);
+    }
+    let tags: Vec<String> = con.smembers(generate_alias_key(name, digest))?;
+    let tags_key = generate_tags_key(name);
+    for tag in tags {
+        let tagged: Option<String> = con.hget(&tags_key, &tag)?;
+        if tagged.as_deref() != Some(digest) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Run a consistency check every `interval`, off the request handling
+/// threads, counting its findings in the `/metrics` counters
+pub fn spawn_checks(
+    connection_pool: Pool<Client>,
+    storage: Filesystem,
+    interval: Duration,
+    sample_rate: f64,
+    usage: StorageUsage,
+) {
+    tokio::spawn(async move {
+        let start = tokio::time::Instant::now() + interval;
+        let mut interval = tokio::time::interval_at(start, interval);
+        loop {
+            interval.tick().await;
+            let (connection_pool, storage) = (connection_pool.clone(), storage.clone());
+            let checked = tokio::task::spawn_blocking(move || {
+                let mut con = connection_pool.get()?;
+                check(con.deref_mut(), &storage, sample_rate)
+            })
+            .await;
+            match checked {
+                Ok(Ok(findings)) => usage.consistency_checked(&findings),
+                Ok(Err(err)) => log::warn!("couldn't check consistency: {}", err),
+                Err(err) => log::warn!("consistency check stopped: {}", err),
+            }
+        }
+    });
+}
@@ -0,0 +1,530 @@
+use super::metadata::ManifestMetadata;
+use super::platform::MANIFEST_LIST_MEDIA_TYPE;
+use super::validation::ACCEPTED_MANIFEST_MEDIA_TYPES;
+use super::{
+    check_pushed, mirror_store, store, validate_name, Manifest, PushConditions, PushedManifest,
+    Stored, INDEX_MEDIA_TYPE,
+};
+use crate::config::Config;
+use crate::error::RegistryError;
+use crate::policy::{check_not_blocked, check_not_read_only, is_immutable_tag};
+use crate::reference::Reference;
+use crate::replica::Replica;
+use crate::storage::{Filesystem, StorageBackend};
+use crate::tags::{is_accepted_digest, is_tag_name_valid, sha256_digest, ContentHasher};
+use crate::Descriptor;
+
+use anyhow::{anyhow, bail, Error, Result};
+
+use std::fmt;
+use std::io::Write;
+
+use hyper::body::{Bytes, HttpBody};
+use hyper::client::HttpConnector;
+use hyper::header::{ACCEPT, AUTHORIZATION, LINK, LOCATION, WWW_AUTHENTICATE};
+use hyper::{Body, HeaderMap, Request, Response, StatusCode};
+
+use hyper_tls::HttpsConnector;
+
+use r2d2::Pool;
+
+use redis::Client;
+
+use rocket::http::RawStr;
+use rocket::serde::json::serde_json;
+use rocket::serde::Deserialize;
+
+/// Redirects followed to download a blob, e.g. to a CDN
+const MAX_REDIRECTS: usize = 5;
+
+/// Tags listed per page of the source registry when cloning every tag
+const TAGS_PAGE_SIZE: usize = 100;
+
+/// Repository of another registry to clone from, e.g.
+/// `registry-1.docker.io/library/ubuntu:22.04`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Source {
+    /// Base URL of the registry, `https` unless given, e.g.
+    /// `https://registry-1.docker.io`
+    pub registry: String,
+    /// The repository, tag and digest cloned
+    pub reference: Reference,
+}
+
+impl Source {
+    /// Parse `[scheme://]<registry>/<repository>[:tag][@digest]`
+    pub fn parse(source: &str) -> Result<Source> {
+        let (scheme, source) = match source.split_once("://") {
+            Some((scheme, source)) => (scheme, source),
+            None => ("https", source),
+        };
+        let (host, reference) = source
+            .split_once('/')
+            .ok_or_else(|| anyhow!("`{}` doesn't name a registry", source))?;
+        let reference = reference
+            .parse::<Reference>()
+            .map_err(|err| anyhow!("{}: {}", err.message(), err.detail()))?;
+        Ok(Source {
+            registry: format!("{}://{}", scheme, host),
+            reference,
+        })
+    }
+
+    /// The tag or digest pulled, `None` when every tag is cloned
+    fn pulled(&self) -> Option<&str> {
+        self.reference
+            .digest
+            .as_deref()
+            .or(self.reference.tag.as_deref())
+    }
+}
+
+/// Step of a clone, reported as it goes
+#[derive(Debug, Clone, PartialEq)]
+pub enum Progress {
+    /// The blob with the given digest is already stored, it isn't downloaded
+    BlobStored(String),
+    /// The blob with the given digest was downloaded and verified, with its
+    /// size in bytes
+    BlobDownloaded(String, u64),
+    /// A manifest was stored under the given tag or digest, with its digest
+    ManifestCloned(String, String),
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Progress::BlobStored(digest) => write!(f, "{}: already stored", digest),
+            Progress::BlobDownloaded(digest, size) => {
+                write!(f, "{}: downloaded {} bytes", digest, size)
+            }
+            Progress::ManifestCloned(reference, digest) => {
+                write!(f, "{}: cloned {}", reference, digest)
+            }
+        }
+    }
+}
+
+/// `Bearer` challenge of a registry requiring a token, e.g.
+/// `Bearer realm="https://auth.docker.io/token",service="registry.docker.io"`
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct BearerChallenge {
+    pub realm: String,
+    pub service: Option<String>,
+    pub scope: Option<String>,
+}
+
+impl BearerChallenge {
+    /// Parse a `WWW-Authenticate` header, `None` unless it's a `Bearer`
+    /// challenge with a realm
+    pub fn parse(header: &str) -> Option<BearerChallenge> {
+        let parameters = header.strip_prefix("Bearer ")?;
+        let mut challenge = BearerChallenge::default();
+        // quoted values, e.g. scopes, may contain commas
+        for parameter in parameters.split("\",") {
+            let (key, value) = parameter.split_once('=')?;
+            let value = value.trim().trim_matches('"').to_string();
+            match key.trim() {
+                "realm" => challenge.realm = value,
+                "service" => challenge.service = Some(value),
+                "scope" => challenge.scope = Some(value),
+                _ => {}
+            }
+        }
+        (!challenge.realm.is_empty()).then_some(challenge)
+    }
+
+    /// URL a token is requested from
+    fn token_url(&self) -> String {
+        let parameters: Vec<String> = [("service", &self.service), ("scope", &self.scope)]
+            .iter()
+            .filter_map(|(name, value)| {
+                let value = value.as_deref()?;
+                Some(format!("{}={}", name, RawStr::new(value).percent_encode()))
+            })
+            .collect();
+        if parameters.is_empty() {
+            self.realm.clone()
+        } else {
+            format!("{}?{}", self.realm, parameters.join("&"))
+        }
+    }
+}
+
+/// Page of the tags of the source repository, `null` when it has none
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct TagsResponse {
+    tags: Option<Vec<String>>,
+}
+
+/// Token answered by the auth server of a registry, older ones naming it
+/// `access_token`
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct TokenResponse {
+    token: Option<String>,
+    access_token: Option<String>,
+}
+
+/// Client pulling from the source registry, authenticating with a token
+/// when challenged
+struct SourceClient {
+    client: hyper::Client<HttpsConnector<HttpConnector>>,
+    source: Source,
+    /// `user:password` sent to the auth server, anonymous tokens otherwise
+    credentials: Option<String>,
+    token: Option<String>,
+}
+
+impl SourceClient {
+    /// Pull a manifest of the source repository, e.g. `manifests/latest`,
+    /// reading its whole body
+    async fn pull(&mut self, path: &str, accept: &str) -> Result<(HeaderMap, Bytes)> {
+        let response = self.open(path, accept).await?;
+        let headers = response.headers().clone();
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        Ok((headers, body))
+    }
+
+    /// Request a manifest or a blob of the source repository, following
+    /// redirects, leaving the body to be streamed
+    async fn open(&mut self, path: &str, accept: &str) -> Result<Response<Body>> {
+        let url = format!(
+            "{}/v2/{}/{}",
+            self.source.registry, self.source.reference.repository, path
+        );
+        let mut response = self.send(&url, accept, true).await?;
+        let challenge = response
+            .headers()
+            .get(WWW_AUTHENTICATE)
+            .and_then(|header| header.to_str().ok())
+            .and_then(BearerChallenge::parse);
+        if let (StatusCode::UNAUTHORIZED, Some(challenge)) = (response.status(), challenge) {
+            self.token = Some(self.authenticate(&challenge).await?);
+            response = self.send(&url, accept, true).await?;
+        }
+        let mut redirects = 0;
+        while response.status().is_redirection() && redirects < MAX_REDIRECTS {
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .ok_or_else(|| anyhow!("{} redirected nowhere", url))?;
+            let location = if location.starts_with('/') {
+                format!("{}{}", self.source.registry, location)
+            } else {
+                location.to_string()
+            };
+            // redirects to a storage are pre-signed, the token stays with
+            // the registry
+            response = self.send(&location, accept, false).await?;
+            redirects += 1;
+        }
+        if !response.status().is_success() {
+            bail!("{} answered {}", url, response.status());
+        }
+        Ok(response)
+    }
+
+    async fn send(&self, url: &str, accept: &str, authorized: bool) -> Result<Response<Body>> {
+        let mut request = Request::get(url).header(ACCEPT, accept);
+        if let (Some(token), true) = (&self.token, authorized) {
+            request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+        Ok(self.client.request(request.body(Body::empty())?).await?)
+    }
+
+    /// Request a token from the auth server named by the challenge
+    async fn authenticate(&self, challenge: &BearerChallenge) -> Result<String> {
+        let mut request = Request::get(challenge.token_url());
+        if let Some(credentials) = &self.credentials {
+            request = request.header(
+                AUTHORIZATION,
+                format!("Basic {}", base64::encode(credentials)),
+            );
+        }
+        let response = self.client.request(request.body(Body::empty())?).await?;
+        if !response.status().is_success() {
+            bail!("{} denied a token: {}", challenge.realm, response.status());
+        }
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        let token: TokenResponse = serde_json::from_slice(&body)?;
+        token
+            .token
+            .or(token.access_token)
+            .ok_or_else(|| anyhow!("{} answered no token", challenge.realm))
+    }
+}
+
+/// Local repository cloned into
+struct Destination<'a> {
+    name: &'a str,
+    storage: Filesystem,
+    config: &'a Config,
+    connection_pool: &'a Pool<Client>,
+    replica: &'a Replica,
+}
+
+/// Clone a repository of another registry into a local repository: the
+/// manifest of the source tag or digest, or of every tag when it names
+/// neither, along with every blob they reference and every manifest of an
+/// image index. Blobs already stored aren't downloaded again, the others are
+/// verified against their digest before being stored.
+///
+/// Cloned manifests go through the same policies and checks as a push, and
+/// are mirrored to the replica. Each step is reported to `progress`, the
+/// references cloned are returned.
+pub async fn clone_repository<P>(
+    source: Source,
+    destination: &str,
+    credentials: Option<String>,
+    config: &Config,
+    connection_pool: &Pool<Client>,
+    replica: &Replica,
+    mut progress: P,
+) -> Result<Vec<String>>
+where
+    P: FnMut(Progress) + Send,
+{
+    validate_name(destination).map_err(rejected)?;
+    check_not_blocked(config, destination).map_err(rejected)?;
+    check_not_read_only(config, destination).map_err(rejected)?;
+    let destination = Destination {
+        name: destination,
+        storage: Filesystem::from_config(config)
+            .ok_or_else(|| anyhow!("cloning needs a storage, set STORAGE_PATH"))?,
+        config,
+        connection_pool,
+        replica,
+    };
+    let pulled = source.pulled().map(str::to_string);
+    let mut client = SourceClient {
+        client: hyper::Client::builder().build(HttpsConnector::new()),
+        source,
+        credentials,
+        token: None,
+    };
+    let references = match pulled {
+        Some(reference) => vec![reference],
+        None => list_tags(&mut client).await?,
+    };
+    for reference in &references {
+        clone_manifest(&mut client, reference, &destination, &mut progress).await?;
+    }
+    Ok(references)
+}
+
+/// List every tag of the source repository, page after page
+async fn list_tags(client: &mut SourceClient) -> Result<Vec<String>> {
+    let mut tags: Vec<String> = Vec::new();
+    loop {
+        let path = match tags.last() {
+            Some(last) => format!("tags/list?n={}&last={}", TAGS_PAGE_SIZE, last),
+            None => format!("tags/list?n={}", TAGS_PAGE_SIZE),
+        };
+        let (headers, body) = client.pull(&path, "application/json").await?;
+        let page: TagsResponse = serde_json::from_slice(&body)?;
+        let previous = tags.last().cloned();
+        for tag in page.tags.unwrap_or_default() {
+            // tags come from the source, they never name a path unchecked
+            if !is_tag_name_valid(&tag) {
+                bail!("malformed tag `{}`", tag);
+            }
+            tags.push(tag);
+        }
+        // registries only link the next page when there's one, a page
+        // bringing no new tag ends the listing anyway
+        if headers.get(LINK).is_none() || tags.last() == previous.as_ref() {
+            return Ok(tags);
+        }
+    }
+}
+
+/// Clone the manifest a tag or digest points at, the manifests of an image
+/// index and the blobs they reference, storing it under the same reference
+async fn clone_manifest(
+    client: &mut SourceClient,
+    reference: &str,
+    destination: &Destination<'_>,
+    progress: &mut (dyn FnMut(Progress) + Send),
+) -> Result<()> {
+    let content = pull_manifest(client, reference).await?;
+    let (manifest, media_type) = check_pushed(&content, destination.config).map_err(rejected)?;
+    match &manifest {
+        PushedManifest::Image(image) => {
+            clone_blobs(client, image, &destination.storage, progress).await?
+        }
+        PushedManifest::Index(index) => {
+            for child in &index.manifests {
+                let child_content = pull_manifest(client, &child.digest).await?;
+                let (child_manifest, child_media_type) =
+                    check_pushed(&child_content, destination.config).map_err(rejected)?;
+                match &child_manifest {
+                    PushedManifest::Image(image) => {
+                        clone_blobs(client, image, &destination.storage, progress).await?
+                    }
+                    PushedManifest::Index(_) => {
+                        bail!("nested index {} isn't supported", child.digest)
+                    }
+                }
+                destination.store(
+                    &child.digest,
+                    &child_content,
+                    &child_manifest,
+                    &child_media_type,
+                )?;
+                progress(Progress::ManifestCloned(
+                    child.digest.clone(),
+                    child.digest.clone(),
+                ));
+            }
+        }
+    }
+    destination.store(reference, &content, &manifest, &media_type)?;
+    progress(Progress::ManifestCloned(
+        reference.to_string(),
+        sha256_digest(&content),
+    ));
+    Ok(())
+}
+
+/// Describe why a destination or a manifest is refused, as a push would be
+fn rejected(err: RegistryError) -> Error {
+    anyhow!("{}", err.detail())
+}
+
+/// Pull a manifest, checking its digest when it's pulled by digest
+async fn pull_manifest(client: &mut SourceClient, reference: &str) -> Result<Vec<u8>> {
+    let accept = ACCEPTED_MANIFEST_MEDIA_TYPES
+        .iter()
+        .chain(&[INDEX_MEDIA_TYPE, MANIFEST_LIST_MEDIA_TYPE])
+        .copied()
+        .collect::<Vec<_>>()
+        .join(", ");
+    let (headers, content) = client
+        .pull(&format!("manifests/{}", reference), &accept)
+        .await?;
+    let digest = sha256_digest(&content);
+    let expected = headers
+        .get("Docker-Content-Digest")
+        .and_then(|digest| digest.to_str().ok())
+        .filter(|digest| digest.starts_with("sha256:"));
+    if reference.contains(':') && reference != digest || expected.is_some_and(|e| e != digest) {
+        bail!("manifest {} doesn't match its digest {}", reference, digest);
+    }
+    Ok(content.to_vec())
+}
+
+/// Download the config and the layers of a manifest which aren't stored
+/// yet, verifying them
+async fn clone_blobs(
+    client: &mut SourceClient,
+    manifest: &Manifest,
+    storage: &Filesystem,
+    progress: &mut (dyn FnMut(Progress) + Send),
+) -> Result<()> {
+    for descriptor in std::iter::once(&manifest.config).chain(&manifest.layers) {
+        // digests come from the source, they never name a path unchecked
+        if !is_accepted_digest(&descriptor.digest) {
+            bail!("malformed blob digest `{}`", descriptor.digest);
+        }
+        if storage.exists(&descriptor.digest) {
+            progress(Progress::BlobStored(descriptor.digest.clone()));
+            continue;
+        }
+        let size = download_blob(client, descriptor, storage).await?;
+        progress(Progress::BlobDownloaded(descriptor.digest.clone(), size));
+    }
+    Ok(())
+}
+
+/// Download a blob into a temporary file of the storage, hashing it as it's
+/// written so it's never held in memory, and move it into place once it
+/// matches its descriptor. Returns its size in bytes.
+async fn download_blob(
+    client: &mut SourceClient,
+    descriptor: &Descriptor,
+    storage: &Filesystem,
+) -> Result<u64> {
+    let mut hasher = descriptor
+        .digest
+        .split_once(':')
+        .and_then(|(algorithm, _)| ContentHasher::new(algorithm))
+        .ok_or_else(|| anyhow!("blob {} has an unsupported digest", descriptor.digest))?;
+    // sizes were checked not to be negative with the manifest
+    let expected = descriptor.size as u64;
+    let mut body = client
+        .open(
+            &format!("blobs/{}", descriptor.digest),
+            "application/octet-stream",
+        )
+        .await?
+        .into_body();
+    let mut temporary = storage.temporary(&descriptor.digest)?;
+    let mut size = 0;
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        size += chunk.len() as u64;
+        if size > expected {
+            bail!("blob {} is bigger than its descriptor", descriptor.digest);
+        }
+        hasher.update(&chunk);
+        temporary.write_all(&chunk)?;
+    }
+    if size != expected || hasher.digest() != descriptor.digest {
+        bail!("blob {} doesn't match its descriptor", descriptor.digest);
+    }
+    storage.persist(&descriptor.digest, temporary)?;
+    Ok(size)
+}
+
+impl Destination<'_> {
+    /// Store a cloned manifest like a push, under a tag or its digest, with
+    /// the media type resolved by the push checks, and mirror it to the
+    /// replica
+    fn store(
+        &self,
+        reference: &str,
+        content: &[u8],
+        manifest: &PushedManifest,
+        media_type: &str,
+    ) -> Result<()> {
+        let digest = sha256_digest(content);
+        let metadata = self
+            .config
+            .manifest_metadata_hash
+            .then(|| ManifestMetadata::of(&digest, content, manifest, Some(media_type)));
+        let stored = store(
+            self.name,
+            reference,
+            &digest,
+            content,
+            Some(media_type),
+            metadata.as_ref(),
+            &PushConditions {
+                if_match: None,
+                immutable: is_immutable_tag(self.config, reference),
+                max_tags: self.config.max_tags_per_repo,
+            },
+            &mut self.connection_pool.get()?,
+        )?;
+        match stored {
+            Stored::TagImmutable => bail!("tag `{}` is immutable", reference),
+            Stored::TagLimitReached => bail!("`{}` has the maximum number of tags", self.name),
+            _ => {
+                mirror_store(
+                    self.replica,
+                    "manifest clone",
+                    self.name,
+                    reference,
+                    &digest,
+                    content.to_vec(),
+                    Some(media_type),
+                    metadata,
+                );
+                Ok(())
+            }
+        }
+    }
+}
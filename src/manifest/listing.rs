@@ -0,0 +1,310 @@
+use super::{generate_alias_key, generate_manifest_key, generate_pushed_key, generate_tags_key};
+use crate::proxy::ExternalOrigin;
+use crate::tags::is_accepted_digest;
+use crate::telemetry::redis_span;
+
+use anyhow::Result;
+
+use r2d2::PooledConnection;
+
+use redis::{Client, Commands, RedisResult};
+
+use rocket::http::{ContentType, RawStr, Status};
+use rocket::response::{self, Responder, Response};
+use rocket::serde::json::serde_json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::Request;
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::Cursor;
+use std::ops::DerefMut;
+
+/// A manifest stored under a repository, with the tags pointing at it
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct ManifestEntry {
+    /// Digest the manifest is stored under
+    pub digest: String,
+    /// Tags pointing at the manifest, sorted
+    pub tags: Vec<String>,
+}
+
+/// Page of the manifests stored under a repository, sorted by digest
+#[derive(Serialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct ManifestList {
+    /// The repository name
+    pub name: String,
+    /// Manifests of this page
+    pub manifests: Vec<ManifestEntry>,
+    /// Last digest of this page, when there's a next one
+    #[serde(skip)]
+    pub next: Option<String>,
+    /// Annotation filters the manifests were listed with, kept for the next page
+    #[serde(skip)]
+    pub annotations: Vec<String>,
+}
+
+/// Page of the tags of a repository, as served by the OCI `tags/list` endpoint
+#[derive(Serialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct TagList {
+    /// The repository name
+    pub name: String,
+    /// Tags of this page, in lexical order
+    pub tags: Vec<String>,
+    /// When each tag of this page was last pushed, in seconds since the
+    /// epoch, only when asked for as it isn't part of the OCI response
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pushed: Option<BTreeMap<String, u64>>,
+    /// Last tag of this page, when there's a next one
+    #[serde(skip)]
+    pub next: Option<String>,
+}
+
+/// Serve a page of tags, linking to the next one:
+/// `Link: </v2/<name>/tags/list?n=<n>&last=<tag>>; rel="next"`
+impl<'r> Responder<'r, 'static> for TagList {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let body = serde_json::to_vec(&self).map_err(|_| Status::InternalServerError)?;
+        let mut response = Response::build();
+        response.header(ContentType::JSON);
+        if let Some(last) = &self.next {
+            let page_size = request.query_value::<usize>("n").and_then(Result::ok);
+            response.raw_header(
+                "Link",
+                format!(
+                    "<{}>; rel=\"next\"",
+                    ExternalOrigin::of(request).url(&format!(
+                        "/v2/{}/tags/list?n={}&last={}{}",
+                        self.name,
+                        page_size.unwrap_or(self.tags.len()),
+                        last,
+                        if self.pushed.is_some() {
+                            "&pushed=true"
+                        } else {
+                            ""
+                        }
+                    ))
+                ),
+            );
+        }
+        response.sized_body(body.len(), Cursor::new(body)).ok()
+    }
+}
+
+/// Annotations of a stored manifest, the rest of it isn't needed to filter
+#[derive(Deserialize, Default)]
+#[serde(crate = "rocket::serde")]
+struct Annotated {
+    #[serde(default)]
+    annotations: HashMap<String, String>,
+}
+
+/// Serve a page of manifests, linking to the next one as the OCI tags
+/// listing does: `Link: </v2/<name>/manifests?n=<n>&last=<digest>>; rel="next"`
+impl<'r> Responder<'r, 'static> for ManifestList {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let body = serde_json::to_vec(&self).map_err(|_| Status::InternalServerError)?;
+        let mut response = Response::build();
+        response.header(ContentType::JSON);
+        if let Some(last) = &self.next {
+            let page_size = request.query_value::<usize>("n").and_then(Result::ok);
+            let filters: String = self
+                .annotations
+                .iter()
+                .map(|annotation| {
+                    format!("&annotation={}", RawStr::new(annotation).percent_encode())
+                })
+                .collect();
+            response.raw_header(
+                "Link",
+                format!(
+                    "<{}>; rel=\"next\"",
+                    ExternalOrigin::of(request).url(&format!(
+                        "/v2/{}/manifests?n={}&last={}{}",
+                        self.name,
+                        page_size.unwrap_or(self.manifests.len()),
+                        last,
+                        filters
+                    ))
+                ),
+            );
+        }
+        response.sized_body(body.len(), Cursor::new(body)).ok()
+    }
+}
+
+/// List the manifests stored under a repository, after the `last` digest
+/// and up to `page_size` of them.
+///
+/// Only the manifests having every annotation are listed, an annotation
+/// being either `key=value` or just `key` to match any value. There's no
+/// annotation index, filtering reads every manifest of the repository.
+pub fn list(
+    name: &str,
+    page_size: Option<usize>,
+    last: Option<&str>,
+    annotations: Vec<String>,
+    con: &mut PooledConnection<Client>,
+) -> Result<ManifestList> {
+    let mut digests = stored_digests(name, con)?;
+    digests.retain(|digest| last.is_none_or(|last| digest.as_str() > last));
+    if !annotations.is_empty() {
+        let mut matching = Vec::new();
+        for digest in digests {
+            if has_annotations(name, &digest, &annotations, con)? {
+                matching.push(digest);
+            }
+        }
+        digests = matching;
+    }
+    let (manifests, next) = page(name, digests, page_size, con)?;
+    Ok(ManifestList {
+        name: name.to_string(),
+        manifests,
+        next,
+        annotations,
+    })
+}
+
+/// Read up to `page_size` of the listed digests with their tags, along with
+/// the last digest of the page when more digests are left.
+///
+/// Manifests deleted since their digests were scanned are skipped, so the
+/// page may be followed by an empty one.
+pub fn page(
+    name: &str,
+    digests: Vec<String>,
+    page_size: Option<usize>,
+    con: &mut PooledConnection<Client>,
+) -> Result<(Vec<ManifestEntry>, Option<String>)> {
+    let mut remaining = digests.into_iter().peekable();
+    let mut manifests = Vec::new();
+    while page_size.is_none_or(|page_size| manifests.len() < page_size) {
+        let digest = match remaining.next() {
+            Some(digest) => digest,
+            None => break,
+        };
+        if let Some(entry) = stored_entry(name, digest, con)? {
+            manifests.push(entry);
+        }
+    }
+    let next = match remaining.peek() {
+        Some(_) => manifests.last().map(|entry| entry.digest.clone()),
+        None => None,
+    };
+    Ok((manifests, next))
+}
+
+/// Read the tags of a listed manifest, in the same transaction as checking
+/// it's still stored, `None` when it was deleted since it was scanned
+fn stored_entry(
+    name: &str,
+    digest: String,
+    con: &mut PooledConnection<Client>,
+) -> Result<Option<ManifestEntry>> {
+    let key = &generate_manifest_key(name, &digest);
+    let alias_key = &generate_alias_key(name, &digest);
+    let (exists, mut tags): (bool, Vec<String>) = redis_span("MULTI", key, || {
+        redis::pipe()
+            .atomic()
+            .exists(key)
+            .smembers(alias_key)
+            .query(con.deref_mut())
+    })?;
+    if !exists {
+        return Ok(None);
+    }
+    tags.sort();
+    Ok(Some(ManifestEntry { digest, tags }))
+}
+
+/// List the tags of a repository after the `last` tag and up to `page_size`
+/// of them, along with when they were last pushed when `with_pushed`.
+///
+/// Tags are sorted by comparing their bytes, which is lexical ordering and
+/// not semantic versioning: `v10` comes before `v2`.
+pub fn tag_page(
+    name: &str,
+    page_size: Option<usize>,
+    last: Option<&str>,
+    with_pushed: bool,
+    con: &mut PooledConnection<Client>,
+) -> RedisResult<TagList> {
+    let tags_key = &generate_tags_key(name);
+    let mut tags: Vec<String> = redis_span("HKEYS", tags_key, || con.hkeys(tags_key))?;
+    tags.sort();
+    tags.retain(|tag| last.is_none_or(|last| tag.as_str() > last));
+    let next = match page_size {
+        Some(page_size) if tags.len() > page_size => {
+            tags.truncate(page_size);
+            tags.last().cloned()
+        }
+        _ => None,
+    };
+    let pushed = match (with_pushed, tags.is_empty()) {
+        (true, false) => {
+            let pushed_key = &generate_pushed_key(name);
+            // `HGET` would be sent for a single tag, answering no array
+            let times: Vec<Option<u64>> = redis_span("HMGET", pushed_key, || {
+                redis::cmd("HMGET")
+                    .arg(pushed_key)
+                    .arg(&tags)
+                    .query(con.deref_mut())
+            })?;
+            Some(
+                tags.iter()
+                    .zip(times)
+                    .filter_map(|(tag, time)| Some((tag.clone(), time?)))
+                    .collect(),
+            )
+        }
+        (true, true) => Some(BTreeMap::new()),
+        (false, _) => None,
+    };
+    Ok(TagList {
+        name: name.to_string(),
+        tags,
+        pushed,
+        next,
+    })
+}
+
+/// Check if a stored manifest has every annotation
+fn has_annotations(
+    name: &str,
+    digest: &str,
+    annotations: &[String],
+    con: &mut PooledConnection<Client>,
+) -> Result<bool> {
+    let key = &generate_manifest_key(name, digest);
+    let content: Option<Vec<u8>> = redis_span("GET", key, || con.get(key))?;
+    let manifest = content
+        .and_then(|content| serde_json::from_slice::<Annotated>(&content).ok())
+        .unwrap_or_default();
+    Ok(annotations
+        .iter()
+        .all(|annotation| match annotation.split_once('=') {
+            Some((key, value)) => manifest.annotations.get(key).map(String::as_str) == Some(value),
+            None => manifest.annotations.contains_key(annotation),
+        }))
+}
+
+/// Every digest a manifest is stored under in the repository, sorted
+fn stored_digests(name: &str, con: &mut PooledConnection<Client>) -> Result<Vec<String>> {
+    let prefix = generate_manifest_key(name, "");
+    let pattern = &format!("{}*", prefix);
+    let keys: Vec<String> = redis_span("SCAN", pattern, || {
+        con.scan_match(pattern).map(|keys| keys.collect())
+    })?;
+    let mut digests: Vec<String> = keys
+        .iter()
+        .filter_map(|key| key.strip_prefix(&prefix))
+        .filter(|reference| is_accepted_digest(reference))
+        .map(str::to_string)
+        .collect();
+    digests.sort();
+    digests.dedup();
+    Ok(digests)
+}
@@ -0,0 +1,103 @@
+use super::{
+    generate_manifest_key, generate_media_type_key, generate_metadata_key, PushedManifest,
+};
+use crate::telemetry::redis_span;
+
+use anyhow::Result;
+
+use r2d2::PooledConnection;
+
+use redis::{Client, Commands};
+
+use rocket::serde::Serialize;
+
+use std::collections::HashMap;
+
+/// Fields of a stored manifest, indexed in a redis hash next to its content
+/// so they're read without deserializing it
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(crate = "rocket::serde", rename_all = "camelCase")]
+pub struct ManifestMetadata {
+    /// Digest the manifest is stored under
+    pub digest: String,
+    /// Media type the manifest is served with
+    pub media_type: String,
+    /// Size of the manifest content, in bytes
+    pub size: usize,
+    /// Number of layers, `0` for an image index
+    pub layers: usize,
+}
+
+impl ManifestMetadata {
+    /// Metadata of a pushed manifest, `media_type` being the one resolved
+    /// for it when the manifest has none
+    pub fn of(
+        digest: &str,
+        content: &[u8],
+        manifest: &PushedManifest,
+        media_type: Option<&str>,
+    ) -> ManifestMetadata {
+        ManifestMetadata {
+            digest: digest.to_string(),
+            media_type: media_type.unwrap_or(manifest.media_type()).to_string(),
+            size: content.len(),
+            layers: match manifest {
+                PushedManifest::Image(manifest) => manifest.layers.len(),
+                PushedManifest::Index(_) => 0,
+            },
+        }
+    }
+
+    /// Fields of the hash, as `HSET` arguments
+    pub fn fields(&self) -> [(&'static str, String); 4] {
+        [
+            ("digest", self.digest.clone()),
+            ("mediaType", self.media_type.clone()),
+            ("size", self.size.to_string()),
+            ("layers", self.layers.to_string()),
+        ]
+    }
+
+    /// Metadata read back from the hash, `None` when a field is missing
+    fn from_fields(fields: &HashMap<String, String>) -> Option<ManifestMetadata> {
+        Some(ManifestMetadata {
+            digest: fields.get("digest")?.clone(),
+            media_type: fields.get("mediaType")?.clone(),
+            size: fields.get("size")?.parse().ok()?,
+            layers: fields.get("layers")?.parse().ok()?,
+        })
+    }
+}
+
+/// Metadata of a stored manifest, from its hash when it was indexed on push
+/// and by deserializing it otherwise, `None` when it isn't stored
+pub fn manifest_metadata(
+    name: &str,
+    digest: &str,
+    con: &mut PooledConnection<Client>,
+) -> Result<Option<ManifestMetadata>> {
+    let metadata_key = &generate_metadata_key(name, digest);
+    let fields: HashMap<String, String> =
+        redis_span("HGETALL", metadata_key, || con.hgetall(metadata_key))?;
+    if let Some(metadata) = ManifestMetadata::from_fields(&fields) {
+        return Ok(Some(metadata));
+    }
+    let keys = [
+        generate_manifest_key(name, digest),
+        generate_media_type_key(name, digest),
+    ];
+    let (content, media_type): (Option<Vec<u8>>, Option<String>) =
+        redis_span("MGET", &keys[0], || con.get(&keys))?;
+    Ok(match content {
+        Some(content) => {
+            let manifest = PushedManifest::parse(&content)?;
+            Some(ManifestMetadata::of(
+                digest,
+                &content,
+                &manifest,
+                media_type.as_deref(),
+            ))
+        }
+        None => None,
+    })
+}
@@ -1,28 +1,83 @@
-use super::tags::{is_accepted_digest, is_tag_name_valid};
+use super::auth::{AdminAccess, DeleteAccess, PullAccess, PushAccess};
+use super::config::Config;
+use super::error::RegistryError;
+use super::metrics::StorageUsage;
+use super::policy::{check_not_blocked, check_not_read_only, is_immutable_tag};
+use super::proxy::ExternalOrigin;
+use super::replica::Replica;
+use super::storage::Filesystem;
+use super::tags::{is_accepted_digest, is_tag_name_valid, normalize_reference, sha256_digest};
+use super::telemetry::{redis_span, ServerTiming, TraceParent};
 use super::Descriptor;
+use cascade::{blob_digests, remove_unreferenced, CascadeSummary};
+use listing::{list, tag_page, ManifestList, TagList};
+use metadata::ManifestMetadata;
+use platform::{accepts_index, select_platform};
+use validation::{
+    validate_blobs, validate_descriptors, validate_index, validate_layers, validate_schema,
+    ValidationProblem, ValidationReport,
+};
 
-use anyhow::{bail, Error, Result};
+use anyhow::{Error, Result};
 
 use r2d2::{Pool, PooledConnection};
 
 use redis::{
-    Client, Commands, ConnectionLike, ErrorKind, FromRedisValue, RedisError, RedisResult,
+    Client, Commands, Connection, ErrorKind, FromRedisValue, Pipeline, RedisError, RedisResult,
     RedisWrite, ToRedisArgs, Value,
 };
 use regex::Regex;
 
-use rocket::http::Status;
-use rocket::serde::json::Json;
+use rocket::data::{Data, Limits, ToByteUnit};
+use rocket::http::{Accept, ContentType, Header, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::{self, Responder, Response};
+use rocket::serde::json::{serde_json, Json};
 use rocket::serde::{Deserialize, Serialize};
-use rocket::{delete, get, head, State};
+use rocket::{delete, get, head, patch, post, put, Request, State};
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Cursor;
+use std::ops::DerefMut;
+use std::time::Duration;
+
+use tracing::{field, instrument};
 
-use std::collections::HashMap;
-use std::ops::Add;
+pub mod cascade;
+pub mod consistency;
+pub mod import;
+pub mod listing;
+pub mod metadata;
+pub mod platform;
+pub mod reindex;
+pub mod retention;
+pub mod validation;
 
 /// Prefix for storing manifest at Redis
 const MANIFEST_PREFIX_KEY: &str = "manifest";
-/// Suffix for stored alias at Redis
+/// Suffix for the set of tags pointing at a manifest digest
 const MANIFEST_ALIAS_SUFFIX_KEY: &str = "alias";
+/// Suffix for the hash relating each tag of a repository to its digest
+const MANIFEST_TAGS_SUFFIX_KEY: &str = "tags";
+/// Suffix for the hash relating each tag and manifest digest of a repository
+/// to when it was last pushed, in seconds since the epoch
+const MANIFEST_PUSHED_SUFFIX_KEY: &str = "pushed";
+/// Suffix for the media type a manifest digest is served with
+const MANIFEST_MEDIA_TYPE_SUFFIX_KEY: &str = "media_type";
+/// Suffix for the hash indexing the fields of a manifest digest
+const MANIFEST_METADATA_SUFFIX_KEY: &str = "metadata";
+/// How many times a redis read is attempted before giving up
+const REDIS_ATTEMPTS: u32 = 3;
+/// Delay before retrying a redis read, growing with each attempt
+const REDIS_RETRY_DELAY: Duration = Duration::from_millis(50);
+/// Maximum size, in mebibytes, of a pushed manifest, unless configured
+pub const MANIFEST_MAX_SIZE: usize = 4;
+/// Name of the rocket limit applied to pushed manifests
+pub const MANIFEST_LIMIT: &str = "manifest";
+/// Media type given to legacy manifests pushed without one, unless configured
+pub const LEGACY_MANIFEST_MEDIA_TYPE: &str = "application/vnd.docker.distribution.manifest.v2+json";
+/// Media type given to image indexes pushed without one
+pub const INDEX_MEDIA_TYPE: &str = "application/vnd.oci.image.index.v1+json";
 
 /// Represents an [OCI Image manifest](https://github.com/opencontainers/image-spec/blob/main/manifest.md)
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -37,7 +92,18 @@ pub struct Manifest {
     /// When used, this field contains the media type of this document, which differs
     /// from the [descriptor](https://github.com/opencontainers/image-spec/blob/main/descriptor.md#properties)
     /// use of `mediaType`.
+    ///
+    /// Legacy clients may leave it out, see [`Config::legacy_manifest_media_type`].
+    ///
+    /// [`Config::legacy_manifest_media_type`]: crate::config::Config::legacy_manifest_media_type
+    #[serde(default)]
     pub media_type: String,
+    /// This OPTIONAL property contains the type of an artifact when the manifest is
+    /// used for an artifact. This MUST be set when `config.mediaType` is set to the
+    /// [empty value](https://github.com/opencontainers/image-spec/blob/main/manifest.md#guidance-for-an-empty-descriptor).
+    /// If defined, the value MUST comply with [RFC 6838](https://tools.ietf.org/html/rfc6838).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artifact_type: Option<String>,
     /// This REQUIRED property references a configuration object for a container, by digest.
     pub config: Descriptor,
     /// Each item in the array MUST be a [descriptor](https://github.com/opencontainers/image-spec/blob/main/descriptor.md).
@@ -54,11 +120,80 @@ pub struct Manifest {
     pub annotations: HashMap<String, String>,
 }
 
-/// Deserialize the manifest binary from redis to an Object
+/// Represents an [OCI Image index](https://github.com/opencontainers/image-spec/blob/main/image-index.md),
+/// pointing at the manifests of an image for each platform
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde", rename_all = "camelCase")]
+pub struct ImageIndex {
+    /// This REQUIRED property specifies the image manifest schema version,
+    /// it MUST be `2`.
+    pub schema_version: usize,
+    /// This property SHOULD be used and contain the media type
+    /// `application/vnd.oci.image.index.v1+json`.
+    #[serde(default)]
+    pub media_type: String,
+    /// This OPTIONAL property contains the type of an artifact when the index is
+    /// used for an artifact.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artifact_type: Option<String>,
+    /// This REQUIRED property contains a list of manifests for specific platforms.
+    pub manifests: Vec<Descriptor>,
+    /// This OPTIONAL property contains arbitrary metadata for the image index.
+    #[serde(default)]
+    pub annotations: HashMap<String, String>,
+}
+
+/// A pushed manifest, either an image manifest or an image index
+#[derive(Debug, Clone)]
+pub enum PushedManifest {
+    Image(Manifest),
+    Index(ImageIndex),
+}
+
+impl PushedManifest {
+    /// Deserialize a pushed manifest, telling its kind apart by its shape
+    /// as clients may leave `mediaType` out: an image index has a
+    /// `manifests` array and no `layers`
+    pub fn parse(body: &[u8]) -> Result<PushedManifest, serde_json::Error> {
+        let value: serde_json::Value = serde_json::from_slice(body)?;
+        let is_index = value
+            .get("manifests")
+            .is_some_and(|manifests| manifests.is_array())
+            && value.get("layers").is_none();
+        if is_index {
+            serde_json::from_value(value).map(PushedManifest::Index)
+        } else {
+            serde_json::from_value(value).map(PushedManifest::Image)
+        }
+    }
+
+    /// The `mediaType` field, empty when it was left out
+    pub fn media_type(&self) -> &str {
+        match self {
+            PushedManifest::Image(manifest) => &manifest.media_type,
+            PushedManifest::Index(index) => &index.media_type,
+        }
+    }
+}
+
+/// Manifest exactly as it was pushed, kept byte for byte so its digest
+/// doesn't change
+pub struct RawManifest(pub Vec<u8>);
+
+/// Deserialize the manifest JSON from redis to an Object
 impl FromRedisValue for Manifest {
+    fn from_redis_value(v: &Value) -> RedisResult<Self> {
+        let RawManifest(bytes) = RawManifest::from_redis_value(v)?;
+        serde_json::from_slice(&bytes)
+            .map_err(|_| RedisError::from((ErrorKind::TypeError, "Couldn't deserialize manifest")))
+    }
+}
+
+/// Read the manifest JSON from redis without deserializing it
+impl FromRedisValue for RawManifest {
     fn from_redis_value(v: &Value) -> RedisResult<Self> {
         match *v {
-            Value::Data(ref bytes) => Ok(bincode::deserialize(bytes).unwrap()),
+            Value::Data(ref bytes) => Ok(RawManifest(bytes.clone())),
             Value::Nil => Err(RedisError::from((
                 ErrorKind::IoError,
                 "Couldn't find manifest",
@@ -68,87 +203,934 @@ impl FromRedisValue for Manifest {
     }
 }
 
-/// Serialize a manifest object to binary
+/// Serialize a manifest object to JSON
 impl ToRedisArgs for Manifest {
     fn write_redis_args<W>(&self, vec: &mut W)
     where
         W: ?Sized + RedisWrite,
     {
-        let bytes = bincode::serialize(self).unwrap();
+        let bytes = serde_json::to_vec(self).unwrap();
         vec.write_arg(bytes.as_slice())
     }
 }
 
+/// Response of a successfully pushed manifest
+#[derive(rocket::Responder)]
+#[response(status = 201)]
+pub struct ManifestCreated {
+    inner: (),
+    location: Header<'static>,
+    digest: Header<'static>,
+}
+
+/// Response of a manifest push
+#[derive(rocket::Responder)]
+pub enum ManifestPush {
+    Created(ManifestCreated),
+    /// The tag didn't point at a digest given by `If-Match`
+    #[response(status = 412)]
+    PreconditionFailed(()),
+    /// The tag matches `IMMUTABLE_TAG_PATTERNS` and already points at
+    /// another digest
+    #[response(status = 409)]
+    Conflict(RegistryError),
+}
+
+/// Response of a manifest delete
+#[derive(rocket::Responder)]
+pub enum ManifestDeletion {
+    /// `202 Accepted` once deleted, `404 Not Found` when it wasn't stored
+    Status(Status),
+    /// The blobs removed along with the manifest, when `DELETE_CASCADE_BLOBS`
+    /// is enabled
+    #[response(status = 202)]
+    Cascaded(Json<CascadeSummary>),
+}
+
+/// Outcome of storing a manifest
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stored {
+    /// The manifest wasn't stored before
+    New,
+    /// The manifest was already stored, only its tag may have moved
+    Existing,
+    /// The reference didn't point at a digest given by `If-Match`, nothing
+    /// was stored
+    PreconditionFailed,
+    /// The tag is immutable and already points at another digest, nothing
+    /// was stored
+    TagImmutable,
+    /// The tag is new and the repository already has as many tags as
+    /// allowed, nothing was stored
+    TagLimitReached,
+}
+
+impl Stored {
+    /// Response of a push which wasn't stored because one of its conditions
+    /// isn't met, `None` once stored
+    fn rejection(self, name: &str, reference: &str) -> Option<Result<ManifestPush, RegistryError>> {
+        match self {
+            Stored::PreconditionFailed => Some(Ok(ManifestPush::PreconditionFailed(()))),
+            Stored::TagImmutable => Some(Ok(ManifestPush::Conflict(RegistryError::Denied(
+                format!("tag `{}` is immutable", reference),
+            )))),
+            Stored::TagLimitReached => Some(Err(RegistryError::Denied(format!(
+                "repository `{}` already has the maximum number of tags",
+                name
+            )))),
+            Stored::New | Stored::Existing => None,
+        }
+    }
+}
+
+/// Entity tags of the `If-Match` header, the digests a reference must point
+/// at for a push to overwrite it, `*` matching any digest
+pub struct IfMatch(Vec<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IfMatch {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let entity_tags: Vec<String> = request
+            .headers()
+            .get("If-Match")
+            .flat_map(|value| value.split(','))
+            .map(|entity_tag| {
+                let entity_tag = entity_tag.trim();
+                let entity_tag = entity_tag.strip_prefix("W/").unwrap_or(entity_tag);
+                normalize_reference(entity_tag.trim_matches('"'))
+            })
+            .collect();
+        if entity_tags.is_empty() {
+            Outcome::Forward(())
+        } else {
+            Outcome::Success(IfMatch(entity_tags))
+        }
+    }
+}
+
+impl IfMatch {
+    /// Check the digest a reference points at matches, a missing reference
+    /// never does
+    fn matches(&self, digest: Option<&str>) -> bool {
+        digest.is_some_and(|digest| {
+            self.0
+                .iter()
+                .any(|entity_tag| entity_tag == "*" || entity_tag == digest)
+        })
+    }
+}
+
+/// Response of a pulled manifest, gzip encoded by the [`Gzip`] fairing when
+/// the client accepts it and the manifest is big enough
+///
+/// [`Gzip`]: crate::compression::Gzip
+///
+/// Manifests are served with the media type resolved when pushed, along with
+/// a `Warning` when it's one of the `DEPRECATED_MEDIA_TYPES`.
+pub struct ManifestResponse {
+    content: RawManifest,
+    media_type: Option<String>,
+    /// Steps of the pull timed so far, sent back when `DEBUG_TIMING` is set
+    timing: Option<ServerTiming>,
+}
+
+impl<'r> Responder<'r, 'static> for ManifestResponse {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let RawManifest(body) = self.content;
+        let mut timing = self.timing;
+        let digest = match &mut timing {
+            Some(timing) => timing.time("digest", || sha256_digest(&body)),
+            None => sha256_digest(&body),
+        };
+        let content_type = self
+            .media_type
+            .as_deref()
+            .and_then(ContentType::parse_flexible)
+            .unwrap_or(ContentType::JSON);
+        let mut response = Response::build();
+        response
+            .header(content_type)
+            .raw_header("ETag", format!("\"{}\"", digest))
+            .raw_header("Docker-Content-Digest", digest);
+        if let Some(timing) = timing {
+            response.raw_header("Server-Timing", timing.header_value());
+        }
+        let deprecated = self.media_type.as_deref().filter(|media_type| {
+            request.rocket().state::<Config>().is_some_and(|config| {
+                config
+                    .deprecated_media_types
+                    .iter()
+                    .any(|deprecated| deprecated == media_type)
+            })
+        });
+        if let Some(media_type) = deprecated {
+            // RFC 7234 miscellaneous persistent warning, without an agent
+            response.raw_header(
+                "Warning",
+                format!("299 - \"media type {} is deprecated\"", media_type),
+            );
+        }
+        response.sized_body(body.len(), Cursor::new(body)).ok()
+    }
+}
+
 /// Check if the manifest exists
 #[head("/<name>/manifests/<reference>")]
+#[instrument(name = "check_manifest", skip_all, fields(repository = %name, reference = %reference, request_id = field::Empty))]
 pub async fn check_manifest(
     name: &str,
     reference: &str,
     connection_pool: &State<Pool<Client>>,
-) -> Status {
-    if !is_valid_request(name, reference) {
-        return Status::NotFound;
-    }
-    let mut con = connection_pool.get().unwrap();
-    let exists_key = manifest_exist(name, reference, &mut con).expect("couldn't find keys");
-    if exists_key {
-        Status::Ok
-    } else {
-        Status::NotFound
+    _access: PullAccess,
+    trace_parent: TraceParent,
+) -> Result<Status, RegistryError> {
+    trace_parent.adopt();
+    let reference = match pulled_reference(name, reference)? {
+        Some(reference) => reference,
+        None => return Ok(Status::NotFound),
+    };
+    match with_retries(connection_pool, |con| manifest_exist(name, &reference, con)).await {
+        Ok(true) => Ok(Status::Ok),
+        Ok(false) => Ok(Status::NotFound),
+        Err(err) => Err(unavailable(err)),
+    }
+}
+
+/// Check if a repository exists, having at least one manifest or tag,
+/// without listing its tags:
+/// - `name`: The repository name
+#[head("/<name>")]
+#[instrument(name = "check_repository", skip_all, fields(repository = %name, request_id = field::Empty))]
+pub async fn check_repository(
+    name: &str,
+    connection_pool: &State<Pool<Client>>,
+    _access: PullAccess,
+    trace_parent: TraceParent,
+) -> Result<Status, RegistryError> {
+    trace_parent.adopt();
+    validate_name(name)?;
+    match with_retries(connection_pool, |con| repository_exists(name, con)).await {
+        Ok(true) => Ok(Status::Ok),
+        Ok(false) => Ok(Status::NotFound),
+        Err(err) => Err(unavailable(err)),
     }
 }
 
 /// Get a manifest using:
 /// - `name`: The manifest name
 /// - `reference`: The manifest tag or digest
+///
+/// The manifest is served exactly as stored, without being deserialized.
+/// The `Docker-Content-Digest` header is always the digest of the
+/// uncompressed manifest.
+///
+/// With `DEBUG_TIMING`, the `Server-Timing` header breaks down how long the
+/// tag resolution, the redis fetch and the digest computation took.
+///
+/// With `INDEX_PLATFORM_SELECTION`, a tag pointing at an image index pulled
+/// by a client whose `Accept` header excludes indexes is served the manifest
+/// of the default platform instead, or `404` when the index has none.
 #[get("/<name>/manifests/<reference>")]
+#[instrument(name = "get_manifest", skip_all, fields(repository = %name, reference = %reference, request_id = field::Empty))]
 pub async fn get_manifest(
     name: &str,
     reference: &str,
     connection_pool: &State<Pool<Client>>,
-) -> Option<Json<Manifest>> {
-    if !is_valid_request(name, reference) {
-        return None;
+    config: &State<Config>,
+    accept: Option<&Accept>,
+    _access: PullAccess,
+    trace_parent: TraceParent,
+) -> Result<Option<ManifestResponse>, RegistryError> {
+    trace_parent.adopt();
+    let reference = match pulled_reference(name, reference)? {
+        Some(reference) => reference,
+        None => return Ok(None),
+    };
+    let mut timing = ServerTiming::default();
+    let mut manifest = with_retries(connection_pool, |con| {
+        served_manifest(name, &reference, con, &mut timing)
+    })
+    .await
+    .map_err(unavailable)?;
+    let default_platform = config
+        .default_platform
+        .as_deref()
+        .filter(|_| !is_accepted_digest(&reference) && !accepts_index(accept));
+    if let (Some(platform), Some(index)) = (default_platform, &manifest) {
+        let RawManifest(content) = &index.content;
+        if let Ok(PushedManifest::Index(_)) = PushedManifest::parse(content) {
+            manifest = match select_platform(content, platform) {
+                Some(digest) => with_retries(connection_pool, |con| {
+                    served_manifest(name, &digest, con, &mut timing)
+                })
+                .await
+                .map_err(unavailable)?,
+                None => None,
+            };
+        }
     }
-    let mut con = connection_pool
-        .get()
-        .expect("couldn't get connection to redis");
-    let manifest = manifest(name, reference, &mut con).expect("couldn't find manifest");
-    Some(Json(manifest))
+    Ok(manifest.map(|manifest| ManifestResponse {
+        timing: config.debug_timing.then_some(timing),
+        ..manifest
+    }))
 }
 
 /// Delete a manifest using:
 /// - `name`: The manifest name
 /// - `reference`: The manifest tag or digest
 ///
-/// Deleting a manifest digest means that all tags will be deleted.
+/// Deleting a manifest digest means that all tags will be deleted. With
+/// `DELETE_CASCADE_BLOBS` enabled, it also removes the blobs no other
+/// manifest references and answers with the removed blobs.
 #[delete("/<name>/manifests/<reference>")]
+#[instrument(name = "delete_manifest", skip_all, fields(repository = %name, reference = %reference, request_id = field::Empty))]
+#[allow(clippy::too_many_arguments)]
 pub async fn delete_manifest(
     name: &str,
     reference: &str,
     connection_pool: &State<Pool<Client>>,
-) -> Status {
-    if !is_valid_request(name, reference) {
-        return Status::NotFound;
-    }
-    let mut con = connection_pool
-        .get()
-        .expect("couldn't get connection to redis");
-    match delete(name, reference, &mut con) {
-        Ok(removed_manifests) => {
-            if removed_manifests > 0 {
-                Status::Accepted
-            } else {
-                Status::NotFound
-            }
+    replica: &State<Replica>,
+    config: &State<Config>,
+    usage: &State<StorageUsage>,
+    _access: DeleteAccess,
+    trace_parent: TraceParent,
+) -> Result<ManifestDeletion, RegistryError> {
+    trace_parent.adopt();
+    let reference = &normalize_reference(reference);
+    validate_name(name)?;
+    check_not_read_only(config, name)?;
+    if !is_valid_reference(reference) {
+        return Ok(ManifestDeletion::Status(Status::NotFound));
+    }
+    let mut con = connection_pool.get()?;
+    let storage = Filesystem::from_config(config)
+        .filter(|_| config.delete_cascade_blobs && is_accepted_digest(reference));
+    let blobs = match storage {
+        Some(_) => con
+            .get::<_, Option<Manifest>>(generate_manifest_key(name, reference))
+            .ok()
+            .flatten()
+            .map(|manifest| blob_digests(&manifest)),
+        None => None,
+    };
+    let removed_manifests = delete(name, reference, &mut con).map_err(unavailable)?;
+    if removed_manifests == 0 {
+        return Ok(ManifestDeletion::Status(Status::NotFound));
+    }
+    let (mirrored_name, mirrored_reference) = (name.to_string(), reference.clone());
+    replica.mirror("manifest delete", move |con| {
+        delete(&mirrored_name, &mirrored_reference, con)
+    });
+    if is_accepted_digest(reference) {
+        usage.manifest_removed();
+    }
+    match (storage, blobs) {
+        (Some(storage), Some(blobs)) => remove_unreferenced(reference, blobs, &storage, &mut con)
+            .map(|summary| ManifestDeletion::Cascaded(Json(summary)))
+            .map_err(|err| {
+                log::error!("couldn't remove the blobs of {}: {}", reference, err);
+                RegistryError::Unknown(format!(
+                    "manifest deleted but its blobs couldn't be removed: {}",
+                    err
+                ))
+            }),
+        _ => Ok(ManifestDeletion::Status(Status::Accepted)),
+    }
+}
+
+/// List the manifests stored under a repository, with the tags pointing at
+/// each one:
+/// - `name`: The manifest name
+/// - `n`: Maximum number of manifests to return
+/// - `last`: Digest the listing starts after
+/// - `annotation`: Annotations the manifests must have, as `key=value` or
+///   `key`, e.g. `org.opencontainers.image.vendor=acme`
+///
+/// This endpoint isn't part of the OCI Distribution specification, it gives
+/// UIs and cleanup tooling the full contents of a repository, so it's only
+/// answered to the `ADMIN_USERS`. Filtering by annotation falls back to
+/// reading every manifest of the repository.
+#[get("/<name>/manifests?<n>&<last>&<annotation>")]
+#[instrument(name = "list_manifests", skip_all, fields(repository = %name, request_id = field::Empty))]
+pub async fn list_manifests(
+    name: &str,
+    n: Option<usize>,
+    last: Option<&str>,
+    annotation: Vec<String>,
+    connection_pool: &State<Pool<Client>>,
+    _access: AdminAccess,
+    trace_parent: TraceParent,
+) -> Result<ManifestList, RegistryError> {
+    trace_parent.adopt();
+    validate_name(name)?;
+    let mut con = connection_pool.get()?;
+    list(name, n, last, annotation, &mut con).map_err(unavailable)
+}
+
+/// List the tags of a repository, as the OCI `tags/list` endpoint:
+/// - `name`: The manifest name
+/// - `n`: Maximum number of tags to return
+/// - `last`: Tag the listing starts after
+///
+/// - `pushed`: `true` to add when each tag was last pushed, in seconds since
+///   the epoch, under a non-standard `pushed` object
+///
+/// Tags are in lexical order, comparing their bytes, so `v10` comes before
+/// `v2`. They're never sorted as semantic versions.
+#[get("/<name>/tags/list?<n>&<last>&<pushed>")]
+#[instrument(name = "list_tags", skip_all, fields(repository = %name, request_id = field::Empty))]
+pub async fn list_tags(
+    name: &str,
+    n: Option<usize>,
+    last: Option<&str>,
+    pushed: Option<bool>,
+    connection_pool: &State<Pool<Client>>,
+    _access: PullAccess,
+    trace_parent: TraceParent,
+) -> Result<TagList, RegistryError> {
+    trace_parent.adopt();
+    validate_name(name)?;
+    let with_pushed = pushed.unwrap_or(false);
+    with_retries(connection_pool, |con| {
+        tag_page(name, n, last, with_pushed, con)
+    })
+    .await
+    .map_err(unavailable)
+}
+
+/// When each tag and manifest digest of a repository was last pushed, in
+/// seconds since the epoch
+pub fn pushed_times(
+    name: &str,
+    con: &mut PooledConnection<Client>,
+) -> RedisResult<HashMap<String, u64>> {
+    let pushed_key = &generate_pushed_key(name);
+    redis_span("HGETALL", pushed_key, || con.hgetall(pushed_key))
+}
+
+/// Digest a manifest reference resolves to
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ResolvedDigest {
+    pub digest: String,
+}
+
+/// Resolve a tag to the digest it points at, without fetching the manifest:
+/// - `name`: The manifest name
+/// - `reference`: The manifest tag or digest
+///
+/// This endpoint isn't part of the OCI Distribution specification, it lets
+/// CI tooling track tags cheaply. Answers `404` for unknown tags.
+#[get("/<name>/manifests/<reference>/digest")]
+#[instrument(name = "resolve_manifest", skip_all, fields(repository = %name, reference = %reference, request_id = field::Empty))]
+pub async fn resolve_manifest(
+    name: &str,
+    reference: &str,
+    connection_pool: &State<Pool<Client>>,
+    _access: PullAccess,
+    trace_parent: TraceParent,
+) -> Result<Option<Json<ResolvedDigest>>, RegistryError> {
+    trace_parent.adopt();
+    let reference = match pulled_reference(name, reference)? {
+        Some(reference) => reference,
+        None => return Ok(None),
+    };
+    let digest = with_retries(connection_pool, |con| stored_digest(name, &reference, con))
+        .await
+        .map_err(unavailable)?;
+    Ok(digest.map(|digest| Json(ResolvedDigest { digest })))
+}
+
+/// Blob referenced by a manifest
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(crate = "rocket::serde", rename_all = "camelCase")]
+pub struct ReferencedBlob {
+    pub media_type: String,
+    pub digest: String,
+    pub size: i64,
+}
+
+impl From<&Descriptor> for ReferencedBlob {
+    fn from(descriptor: &Descriptor) -> Self {
+        ReferencedBlob {
+            media_type: descriptor.media_type.clone(),
+            digest: descriptor.digest.clone(),
+            size: descriptor.size,
+        }
+    }
+}
+
+/// Blobs referenced by a manifest, its layers in order
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ManifestBlobs {
+    pub config: ReferencedBlob,
+    pub layers: Vec<ReferencedBlob>,
+}
+
+/// List the config and the layers of a manifest, to pre-fetch or verify them:
+/// - `name`: The manifest name
+/// - `reference`: The manifest tag or digest
+///
+/// This endpoint isn't part of the OCI Distribution specification. Answers
+/// `404` for unknown references, and `400` for image indexes which reference
+/// manifests rather than blobs.
+#[get("/<name>/manifests/<reference>/layers")]
+#[instrument(name = "list_layers", skip_all, fields(repository = %name, reference = %reference, request_id = field::Empty))]
+pub async fn list_layers(
+    name: &str,
+    reference: &str,
+    connection_pool: &State<Pool<Client>>,
+    _access: PullAccess,
+    trace_parent: TraceParent,
+) -> Result<Option<Json<ManifestBlobs>>, RegistryError> {
+    trace_parent.adopt();
+    let reference = match pulled_reference(name, reference)? {
+        Some(reference) => reference,
+        None => return Ok(None),
+    };
+    let manifest = with_retries(connection_pool, |con| {
+        served_manifest(name, &reference, con, &mut ServerTiming::default())
+    })
+    .await
+    .map_err(unavailable)?;
+    let RawManifest(content) = match manifest {
+        Some(manifest) => manifest.content,
+        None => return Ok(None),
+    };
+    match PushedManifest::parse(&content) {
+        Ok(PushedManifest::Image(manifest)) => Ok(Some(Json(ManifestBlobs {
+            config: (&manifest.config).into(),
+            layers: manifest.layers.iter().map(ReferencedBlob::from).collect(),
+        }))),
+        Ok(PushedManifest::Index(_)) => Err(RegistryError::ManifestInvalid(format!(
+            "`{}` is an image index, it references no layers",
+            reference
+        ))),
+        Err(err) => Err(RegistryError::Unknown(err.to_string())),
+    }
+}
+
+/// Validate a manifest without storing it, returning every problem found:
+/// - `name`: The manifest name
+///
+/// This endpoint isn't part of the OCI Distribution specification, it lets
+/// CI pipelines catch bad manifests before pushing them, and is only answered
+/// to the `ADMIN_USERS` as it reads the storage. The manifest is checked as a
+/// push would, and with a `STORAGE_PATH`, the blobs of an image manifest must
+/// also be stored with the sizes it gives.
+#[post("/<name>/manifests/validate", data = "<body>")]
+#[instrument(name = "validate", skip_all, fields(repository = %name, request_id = field::Empty))]
+pub async fn validate(
+    name: &str,
+    body: Data<'_>,
+    config: &State<Config>,
+    limits: &Limits,
+    _access: AdminAccess,
+    trace_parent: TraceParent,
+) -> Result<Json<ValidationReport>, RegistryError> {
+    trace_parent.adopt();
+    validate_name(name)?;
+    let body = read_manifest(body, limits).await?;
+    let mut problems = match check_pushed(&body, config) {
+        Ok(_) => vec![],
+        Err(RegistryError::ManifestProblems(problems)) => problems,
+        Err(err) => vec![ValidationProblem {
+            field: String::new(),
+            message: err.detail(),
+        }],
+    };
+    if let (Some(storage), Ok(PushedManifest::Image(manifest))) = (
+        Filesystem::from_config(config),
+        PushedManifest::parse(&body),
+    ) {
+        problems.extend(validate_blobs(&manifest, &storage));
+    }
+    Ok(Json(problems.into()))
+}
+
+/// Push a manifest using:
+/// - `name`: The manifest name
+/// - `reference`: The manifest tag or digest
+///
+/// When pushing by digest, the digest of the body must match the reference.
+/// A manifest is served with its `mediaType`. One without it is only accepted
+/// when legacy manifests are supported, and is then served with the
+/// configured media type.
+/// Only artifact manifests, with an `artifactType`, may have no layers.
+///
+/// With `If-Match`, the push only applies when the reference currently points
+/// at one of the given digests, as served in the `ETag` of a pull, and fails
+/// with `412 Precondition Failed` otherwise.
+#[put("/<name>/manifests/<reference>", data = "<body>")]
+#[instrument(name = "put_manifest", skip_all, fields(repository = %name, reference = %reference, request_id = field::Empty))]
+#[allow(clippy::too_many_arguments)]
+pub async fn put_manifest(
+    name: &str,
+    reference: &str,
+    body: Data<'_>,
+    connection_pool: &State<Pool<Client>>,
+    replica: &State<Replica>,
+    config: &State<Config>,
+    usage: &State<StorageUsage>,
+    limits: &Limits,
+    if_match: Option<IfMatch>,
+    origin: ExternalOrigin,
+    _access: PushAccess,
+    trace_parent: TraceParent,
+) -> Result<ManifestPush, RegistryError> {
+    trace_parent.adopt();
+    let reference = &normalize_reference(reference);
+    validate_name(name)?;
+    check_not_blocked(config, name)?;
+    check_not_read_only(config, name)?;
+    if !is_valid_reference(reference) {
+        check_digest_reference(reference)?;
+        return Err(RegistryError::TagInvalid(reference.to_string()));
+    }
+    let body = read_manifest(body, limits).await?;
+    let digest = sha256_digest(&body);
+    if is_accepted_digest(reference) && *reference != digest {
+        return Err(RegistryError::DigestInvalid(format!(
+            "expected {} but the manifest digest is {}",
+            reference, digest
+        )));
+    }
+    let (manifest, media_type) = check_pushed(&body, config)?;
+    let media_type = Some(media_type.as_str());
+    let metadata = config
+        .manifest_metadata_hash
+        .then(|| ManifestMetadata::of(&digest, &body, &manifest, media_type));
+    let mut con = connection_pool.get()?;
+    let stored = store(
+        name,
+        reference,
+        &digest,
+        &body,
+        media_type,
+        metadata.as_ref(),
+        &PushConditions {
+            if_match: if_match.as_ref(),
+            immutable: is_immutable_tag(config, reference),
+            max_tags: config.max_tags_per_repo,
+        },
+        &mut con,
+    )
+    .map_err(unavailable)?;
+    if let Some(rejected) = stored.rejection(name, reference) {
+        return rejected;
+    }
+    if stored == Stored::New {
+        usage.manifest_added();
+    }
+    mirror_store(
+        replica,
+        "manifest push",
+        name,
+        reference,
+        &digest,
+        body,
+        media_type,
+        metadata,
+    );
+    Ok(ManifestPush::Created(ManifestCreated {
+        inner: (),
+        location: Header::new(
+            "Location",
+            origin.url(&format!("/v2/{}/manifests/{}", name, digest)),
+        ),
+        digest: Header::new("Docker-Content-Digest", digest),
+    }))
+}
+
+/// Update only the annotations of a stored manifest, given a JSON merge
+/// patch like `{"annotations": {"org.example.label": "value"}}`, a `null`
+/// value removing the annotation:
+/// - `name`: The manifest name
+/// - `reference`: The manifest tag or digest
+///
+/// Changing the content changes the digest: the patched manifest is stored
+/// under its new digest, which is answered as for a push. A patched tag is
+/// moved onto it, unless it was moved since it was read, while the previous
+/// digest is kept. Any other field than `annotations` is rejected.
+#[patch("/<name>/manifests/<reference>", data = "<patch>")]
+#[instrument(name = "patch_manifest", skip_all, fields(repository = %name, reference = %reference, request_id = field::Empty))]
+#[allow(clippy::too_many_arguments)]
+pub async fn patch_manifest(
+    name: &str,
+    reference: &str,
+    patch: Json<serde_json::Value>,
+    connection_pool: &State<Pool<Client>>,
+    replica: &State<Replica>,
+    config: &State<Config>,
+    usage: &State<StorageUsage>,
+    origin: ExternalOrigin,
+    _access: PushAccess,
+    trace_parent: TraceParent,
+) -> Result<Option<ManifestPush>, RegistryError> {
+    trace_parent.adopt();
+    let reference = &normalize_reference(reference);
+    validate_name(name)?;
+    check_not_blocked(config, name)?;
+    check_not_read_only(config, name)?;
+    if !is_valid_reference(reference) {
+        return Ok(None);
+    }
+    let changes = annotation_changes(patch.into_inner())?;
+    let mut con = connection_pool.get()?;
+    let current = match served_manifest(name, reference, &mut con, &mut ServerTiming::default())
+        .map_err(|err| unavailable(err.into()))?
+    {
+        Some(current) => current,
+        None => return Ok(None),
+    };
+    let RawManifest(content) = &current.content;
+    let current_digest = sha256_digest(content);
+    let mut manifest: serde_json::Value = serde_json::from_slice(content)
+        .map_err(|err| RegistryError::ManifestInvalid(err.to_string()))?;
+    let annotations = manifest
+        .as_object_mut()
+        .ok_or_else(|| RegistryError::ManifestInvalid("manifest isn't an object".to_string()))?
+        .entry("annotations")
+        .or_insert_with(|| serde_json::json!({}));
+    let annotations = annotations.as_object_mut().ok_or_else(|| {
+        RegistryError::ManifestInvalid("annotations aren't an object".to_string())
+    })?;
+    for (key, value) in changes {
+        match value {
+            Some(value) => annotations.insert(key, serde_json::Value::String(value)),
+            None => annotations.remove(&key),
+        };
+    }
+    let body = serde_json::to_vec(&manifest)
+        .map_err(|err| RegistryError::ManifestInvalid(err.to_string()))?;
+    let digest = sha256_digest(&body);
+    let media_type = current.media_type.as_deref();
+    let metadata = PushedManifest::parse(&body)
+        .ok()
+        .filter(|_| config.manifest_metadata_hash)
+        .map(|pushed| ManifestMetadata::of(&digest, &body, &pushed, media_type));
+    let if_match = IfMatch(vec![current_digest]);
+    let stored = store(
+        name,
+        reference,
+        &digest,
+        &body,
+        media_type,
+        metadata.as_ref(),
+        &PushConditions {
+            if_match: (!is_accepted_digest(reference)).then_some(&if_match),
+            immutable: is_immutable_tag(config, reference),
+            max_tags: config.max_tags_per_repo,
+        },
+        &mut con,
+    )
+    .map_err(unavailable)?;
+    if let Some(rejected) = stored.rejection(name, reference) {
+        return rejected.map(Some);
+    }
+    if stored == Stored::New {
+        usage.manifest_added();
+    }
+    // patching a digest stores the new manifest without tagging it
+    let stored_reference = if is_accepted_digest(reference) {
+        digest.as_str()
+    } else {
+        reference.as_str()
+    };
+    mirror_store(
+        replica,
+        "manifest patch",
+        name,
+        stored_reference,
+        &digest,
+        body,
+        media_type,
+        metadata,
+    );
+    Ok(Some(ManifestPush::Created(ManifestCreated {
+        inner: (),
+        location: Header::new(
+            "Location",
+            origin.url(&format!("/v2/{}/manifests/{}", name, digest)),
+        ),
+        digest: Header::new("Docker-Content-Digest", digest),
+    })))
+}
+
+/// Annotations set, or removed when `None`, by a manifest patch, which may
+/// change nothing else
+fn annotation_changes(
+    patch: serde_json::Value,
+) -> Result<Vec<(String, Option<String>)>, RegistryError> {
+    let mut patch = match patch {
+        serde_json::Value::Object(patch) => patch,
+        _ => {
+            return Err(RegistryError::ManifestInvalid(
+                "patch isn't an object".to_string(),
+            ))
+        }
+    };
+    let annotations = patch.remove("annotations");
+    if let Some(field) = patch.keys().next() {
+        return Err(RegistryError::ManifestInvalid(format!(
+            "only annotations can be patched, not `{}`",
+            field
+        )));
+    }
+    let annotations = match annotations {
+        Some(serde_json::Value::Object(annotations)) => annotations,
+        _ => {
+            return Err(RegistryError::ManifestInvalid(
+                "patch has no annotations object".to_string(),
+            ))
         }
-        Err(_) => Status::NotFound,
+    };
+    annotations
+        .into_iter()
+        .map(|(key, value)| match value {
+            serde_json::Value::String(value) => Ok((key, Some(value))),
+            serde_json::Value::Null => Ok((key, None)),
+            _ => Err(RegistryError::ManifestInvalid(format!(
+                "annotation `{}` isn't a string",
+                key
+            ))),
+        })
+        .collect()
+}
+
+/// Validate the repository name and normalize the reference of a manifest
+/// pull, shared by `HEAD` and `GET` so they answer alike. `None` for
+/// references which can't name a manifest, answered with `404`.
+///
+/// Authorization is left to the [`PullAccess`] guard both handlers take.
+fn pulled_reference(name: &str, reference: &str) -> Result<Option<String>, RegistryError> {
+    let reference = normalize_reference(reference);
+    validate_name(name)?;
+    Ok(is_valid_reference(&reference).then_some(reference))
+}
+
+/// Read a pushed manifest body, up to the `manifest` limit
+async fn read_manifest(body: Data<'_>, limits: &Limits) -> Result<Vec<u8>, RegistryError> {
+    let limit = limits
+        .get(MANIFEST_LIMIT)
+        .unwrap_or_else(|| MANIFEST_MAX_SIZE.mebibytes());
+    let body = body
+        .open(limit)
+        .into_bytes()
+        .await
+        .map_err(|err| RegistryError::ManifestInvalid(err.to_string()))?;
+    if !body.is_complete() {
+        return Err(RegistryError::TooLarge(format!(
+            "manifest is bigger than {}",
+            limit
+        )));
+    }
+    Ok(body.into_inner())
+}
+
+/// Check a pushed manifest can be stored, shared by pushes and dry runs so
+/// they accept the same manifests, returning it with the media type it's
+/// served with.
+///
+/// With `STRICT_MANIFEST_SCHEMA`, schema problems are reported on their own
+/// as the manifest may not even deserialize.
+fn check_pushed(body: &[u8], config: &Config) -> Result<(PushedManifest, String), RegistryError> {
+    if config.strict_manifest_schema {
+        let problems = validate_schema(body);
+        if !problems.is_empty() {
+            return Err(invalid_manifest(&problems));
+        }
+    }
+    let manifest = PushedManifest::parse(body)
+        .map_err(|err| RegistryError::ManifestInvalid(err.to_string()))?;
+    let media_type = match (
+        &manifest,
+        manifest.media_type(),
+        &config.legacy_manifest_media_type,
+    ) {
+        (_, media_type, _) if !media_type.is_empty() => Some(media_type),
+        (PushedManifest::Index(_), _, _) => Some(INDEX_MEDIA_TYPE),
+        (PushedManifest::Image(_), _, Some(media_type)) => Some(media_type.as_str()),
+        (PushedManifest::Image(_), _, None) => None,
+    };
+    let mut problems = match media_type {
+        Some(_) => vec![],
+        None => vec![ValidationProblem {
+            field: "mediaType".to_string(),
+            message: "missing media type".to_string(),
+        }],
+    };
+    match &manifest {
+        PushedManifest::Image(manifest) => {
+            problems.extend(validate_layers(manifest));
+            problems.extend(validate_descriptors(manifest));
+        }
+        PushedManifest::Index(index) => problems.extend(validate_index(index)),
+    }
+    match media_type {
+        Some(media_type) if problems.is_empty() => {
+            let media_type = media_type.to_string();
+            Ok((manifest, media_type))
+        }
+        _ => Err(invalid_manifest(&problems)),
+    }
+}
+
+/// Report a redis failure which persisted after retrying it, the pool
+/// running out of connections only being temporary
+fn unavailable(err: Error) -> RegistryError {
+    let err = match err.downcast::<r2d2::Error>() {
+        Ok(err) => return RegistryError::from(err),
+        Err(err) => err,
+    };
+    log::error!("couldn't reach redis: {}", err);
+    RegistryError::Unknown("storage temporarily unavailable".to_string())
+}
+
+/// Report every problem of a pushed manifest as `MANIFEST_INVALID`
+fn invalid_manifest(problems: &[ValidationProblem]) -> RegistryError {
+    RegistryError::ManifestProblems(problems.to_vec())
+}
+
+/// Reject a reference meant as a digest, tags having no `:`, which isn't a
+/// valid one, e.g. a truncated `sha256`
+fn check_digest_reference(reference: &str) -> Result<(), RegistryError> {
+    if reference.contains(':') && !is_accepted_digest(reference) {
+        return Err(RegistryError::DigestInvalid(format!(
+            "malformed digest `{}`",
+            reference
+        )));
     }
+    Ok(())
 }
 
 #[doc(hidden)]
-fn is_valid_request(name: &str, reference: &str) -> bool {
-    is_manifest_name_valid(name) && (is_tag_name_valid(reference) || is_accepted_digest(reference))
+fn is_valid_reference(reference: &str) -> bool {
+    is_tag_name_valid(reference) || is_accepted_digest(reference)
+}
+
+/// Check the manifest name, explaining why it's invalid.
+///
+/// Names are never normalized: a name with uppercase letters is rejected
+/// instead of being lowercased, since it would silently point to another
+/// repository.
+pub fn validate_name(name: &str) -> Result<(), RegistryError> {
+    if is_manifest_name_valid(name) {
+        Ok(())
+    } else if name.chars().any(|character| character.is_ascii_uppercase()) {
+        Err(RegistryError::NameInvalid(format!(
+            "repository name `{}` must be lowercase",
+            name
+        )))
+    } else {
+        Err(RegistryError::NameInvalid(format!(
+            "invalid repository name `{}`",
+            name
+        )))
+    }
 }
 
 /// Verify if the manifest name is valid using the regex
@@ -163,6 +1145,108 @@ fn generate_manifest_key<'manifest>(name: &'manifest str, reference: &'manifest
     format!("{}::{}::{}", MANIFEST_PREFIX_KEY, name, reference)
 }
 
+#[doc(hidden)]
+fn generate_tags_key(name: &str) -> String {
+    format!(
+        "{}::{}::{}",
+        MANIFEST_PREFIX_KEY, name, MANIFEST_TAGS_SUFFIX_KEY
+    )
+}
+
+#[doc(hidden)]
+fn generate_pushed_key(name: &str) -> String {
+    format!(
+        "{}::{}::{}",
+        MANIFEST_PREFIX_KEY, name, MANIFEST_PUSHED_SUFFIX_KEY
+    )
+}
+
+/// Count the repositories having a manifest and the manifests stored across
+/// every repository, scanning every manifest key
+pub fn stored_manifest_counts(con: &mut PooledConnection<Client>) -> RedisResult<(u64, u64)> {
+    let pattern = &format!("{}::*", MANIFEST_PREFIX_KEY);
+    let keys: Vec<String> = redis_span("SCAN", pattern, || {
+        con.scan_match(pattern).map(|keys| keys.collect())
+    })?;
+    let mut repositories = HashSet::new();
+    let mut manifests = 0;
+    for (name, reference) in keys.iter().filter_map(|key| {
+        key.strip_prefix(MANIFEST_PREFIX_KEY)?
+            .strip_prefix("::")?
+            .split_once("::")
+    }) {
+        if is_accepted_digest(reference) {
+            repositories.insert(name);
+            manifests += 1;
+        }
+    }
+    Ok((repositories.len() as u64, manifests))
+}
+
+/// Sum the sizes of the config and layers referenced by every stored image
+/// manifest, a blob shared by several manifests being counted for each one.
+///
+/// Compared with the bytes actually stored, it tells how much sharing blobs
+/// across repositories saves. It reads every manifest.
+pub fn referenced_bytes(con: &mut PooledConnection<Client>) -> RedisResult<u64> {
+    let pattern = &format!("{}::*", MANIFEST_PREFIX_KEY);
+    let keys: Vec<String> = redis_span("SCAN", pattern, || {
+        con.scan_match(pattern).map(|keys| keys.collect())
+    })?;
+    let mut bytes = 0;
+    for key in keys.iter().filter(|key| {
+        key.strip_prefix(MANIFEST_PREFIX_KEY)
+            .and_then(|key| key.strip_prefix("::"))
+            .and_then(|key| key.split_once("::"))
+            .is_some_and(|(_, reference)| is_accepted_digest(reference))
+    }) {
+        let content: Option<Vec<u8>> = redis_span("GET", key, || con.get(key))?;
+        if let Some(Ok(PushedManifest::Image(manifest))) =
+            content.map(|content| PushedManifest::parse(&content))
+        {
+            bytes += std::iter::once(&manifest.config)
+                .chain(&manifest.layers)
+                .map(|descriptor| descriptor.size.max(0) as u64)
+                .sum::<u64>();
+        }
+    }
+    Ok(bytes)
+}
+
+/// Every redis key stored for a repository, with its type, e.g. `string`
+/// for a manifest and `set` for the tags pointing at it
+pub fn repository_keys(
+    name: &str,
+    con: &mut PooledConnection<Client>,
+) -> RedisResult<BTreeMap<String, String>> {
+    let pattern = &format!("{}::{}::*", MANIFEST_PREFIX_KEY, name);
+    let keys: Vec<String> = redis_span("SCAN", pattern, || {
+        con.scan_match(pattern).map(|keys| keys.collect())
+    })?;
+    keys.into_iter()
+        .map(|key| {
+            let key_type: String = redis::cmd("TYPE").arg(&key).query(con.deref_mut())?;
+            Ok((key, key_type))
+        })
+        .collect()
+}
+
+#[doc(hidden)]
+fn generate_media_type_key(name: &str, digest: &str) -> String {
+    format!(
+        "{}::{}::{}::{}",
+        MANIFEST_PREFIX_KEY, name, digest, MANIFEST_MEDIA_TYPE_SUFFIX_KEY
+    )
+}
+
+#[doc(hidden)]
+fn generate_metadata_key(name: &str, digest: &str) -> String {
+    format!(
+        "{}::{}::{}::{}",
+        MANIFEST_PREFIX_KEY, name, digest, MANIFEST_METADATA_SUFFIX_KEY
+    )
+}
+
 #[doc(hidden)]
 fn generate_alias_key<'manifest>(name: &'manifest str, digest: &'manifest str) -> String {
     format!(
@@ -171,102 +1255,378 @@ fn generate_alias_key<'manifest>(name: &'manifest str, digest: &'manifest str) -
     )
 }
 
+/// Resolve a reference to the digest of the manifest it points to, looking
+/// tags up in the repository tags hash
+fn resolve_digest(
+    name: &str,
+    reference: &str,
+    con: &mut PooledConnection<Client>,
+) -> RedisResult<Option<String>> {
+    if is_accepted_digest(reference) {
+        return Ok(Some(reference.to_string()));
+    }
+    let tags_key = &generate_tags_key(name);
+    redis_span("HGET", tags_key, || con.hget(tags_key, reference))
+}
+
+/// Search at redis if any key of a repository exists, stopping the scan at
+/// the first one found
+fn repository_exists(name: &str, con: &mut PooledConnection<Client>) -> RedisResult<bool> {
+    let pattern = &format!("{}::{}::*", MANIFEST_PREFIX_KEY, name);
+    redis_span("SCAN", pattern, || {
+        con.scan_match::<_, String>(pattern)
+            .map(|mut keys| keys.next().is_some())
+    })
+}
+
 /// Search at redis if an manifest exists
-fn manifest_exist(name: &str, reference: &str, con: &mut PooledConnection<Client>) -> Result<bool> {
-    let key = &generate_manifest_key(name, reference);
-    let alias_key = &generate_alias_key(name, reference);
-    let exists_key = con.exists(key).expect("connection with redis");
-    let exists_alias = con.exists(alias_key).expect("connection with redis");
-    Ok(exists_key || exists_alias)
-}
-
-/// Retrieves a manifest from redis
-fn manifest(name: &str, reference: &str, con: &mut PooledConnection<Client>) -> Result<Manifest> {
-    let key = generate_manifest_key(name, reference);
-    match con.get(key) {
-        Ok(manifest) => Ok(manifest),
-        Err(_) => {
-            let alias_key = &generate_alias_key(name, reference);
-            let search_alias: RedisResult<Vec<String>> = con.smembers(alias_key);
-            match search_alias {
-                Ok(alias) => {
-                    let existing_alias = alias
-                        .iter()
-                        .filter(|alias_key| manifest_exist(name, alias_key, con).unwrap())
-                        .take(1)
-                        .last()
-                        .expect("couldn't find an existing alias");
-                    manifest(name, existing_alias, con)
+fn manifest_exist(
+    name: &str,
+    reference: &str,
+    con: &mut PooledConnection<Client>,
+) -> RedisResult<bool> {
+    stored_digest(name, reference, con).map(|digest| digest.is_some())
+}
+
+/// Resolve a reference to the digest of a stored manifest, `None` when the
+/// tag is unknown or points at a manifest which isn't stored
+fn stored_digest(
+    name: &str,
+    reference: &str,
+    con: &mut PooledConnection<Client>,
+) -> RedisResult<Option<String>> {
+    match resolve_digest(name, reference, con)? {
+        Some(digest) => {
+            let key = &generate_manifest_key(name, &digest);
+            let exists: bool = redis_span("EXISTS", key, || con.exists(key))?;
+            Ok(exists.then_some(digest))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Retrieves a manifest as stored, with the media type resolved for it when
+/// pushed, `None` when it doesn't exist.
+///
+/// Manifests stored before their media type was recorded are served with
+/// their `mediaType`.
+fn served_manifest(
+    name: &str,
+    reference: &str,
+    con: &mut PooledConnection<Client>,
+    timing: &mut ServerTiming,
+) -> RedisResult<Option<ManifestResponse>> {
+    match timing.time("resolve", || resolve_digest(name, reference, con))? {
+        Some(digest) => {
+            let keys = [
+                generate_manifest_key(name, &digest),
+                generate_media_type_key(name, &digest),
+            ];
+            let (content, media_type): (Option<RawManifest>, Option<String>) =
+                timing.time("fetch", || redis_span("MGET", &keys[0], || con.get(&keys)))?;
+            Ok(content.map(|content| {
+                let media_type = media_type.or_else(|| {
+                    PushedManifest::parse(&content.0)
+                        .ok()
+                        .map(|manifest| manifest.media_type().to_string())
+                        .filter(|media_type| !media_type.is_empty())
+                });
+                ManifestResponse {
+                    content,
+                    media_type,
+                    timing: None,
                 }
-                Err(_) => bail!("Couldn't find manifest"),
-            }
+            }))
         }
+        None => Ok(None),
     }
 }
 
-/// Delete a manifest
-fn delete(name: &str, reference: &str, con: &mut PooledConnection<Client>) -> Result<i8> {
-    let key = generate_manifest_key(name, reference);
-    let result = con
-        .req_command(redis::cmd("GETDEL").arg(key))
-        .map(|value| Manifest::from_redis_value(&value))
-        .unwrap();
-    match result {
-        Ok(manifest) => {
-            let sum = if is_accepted_digest(reference) {
-                search_alias_and_delete_it(name, reference, con)
-            } else {
-                remove_tag_relation_from_digest(name, reference, con, manifest)
+/// Run a redis operation with a pooled connection, retrying it with a new
+/// connection when it fails because of a transient error. Waiting before
+/// a retry doesn't block the worker thread.
+async fn with_retries<T, F>(connection_pool: &Pool<Client>, mut operation: F) -> Result<T>
+where
+    F: FnMut(&mut PooledConnection<Client>) -> RedisResult<T>,
+{
+    let mut attempt = 1;
+    loop {
+        let result = connection_pool
+            .get()
+            .map_err(Error::from)
+            .and_then(|mut con| operation(&mut con).map_err(Error::from));
+        match result {
+            Err(err) if attempt < REDIS_ATTEMPTS && is_transient(&err) => {
+                log::warn!("retrying transient redis error: {}", err);
+                tokio::time::sleep(REDIS_RETRY_DELAY * attempt).await;
+                attempt += 1;
             }
-            .unwrap();
-            Ok(sum)
+            result => return result,
         }
-        Err(_) => search_alias_and_delete_it(name, reference, con),
     }
 }
 
-/// Search manifest by alias and delete it
-fn search_alias_and_delete_it(
+/// Check if a redis error may go away by itself, e.g. a dropped connection
+/// or a server still loading its dataset
+fn is_transient(err: &Error) -> bool {
+    err.downcast_ref::<RedisError>().is_some_and(|err| {
+        err.is_io_error()
+            || err.is_timeout()
+            || err.is_connection_dropped()
+            || err.is_connection_refusal()
+            || matches!(
+                err.kind(),
+                ErrorKind::BusyLoadingError
+                    | ErrorKind::TryAgain
+                    | ErrorKind::ClusterDown
+                    | ErrorKind::MasterDown
+            )
+    })
+}
+
+/// Conditions a push must meet, checked in the same transaction as the
+/// write. Replicas mirror pushes unconditionally.
+#[derive(Default)]
+struct PushConditions<'a> {
+    /// Digests the reference must point at, from `If-Match`
+    if_match: Option<&'a IfMatch>,
+    /// The tag can't be moved to another digest once pushed
+    immutable: bool,
+    /// Maximum number of tags of the repository, from `MAX_TAGS_PER_REPO`
+    max_tags: Option<usize>,
+}
+
+/// Store the manifest content under its digest, along with the media type
+/// it's served with and, when given, the hash indexing its fields. When
+/// pushed by tag, the tag is atomically moved from the digest it pointed to
+/// onto the new one, unless one of the `conditions` isn't met.
+#[allow(clippy::too_many_arguments)]
+fn store(
     name: &str,
     reference: &str,
+    digest: &str,
+    content: &[u8],
+    media_type: Option<&str>,
+    metadata: Option<&ManifestMetadata>,
+    conditions: &PushConditions,
     con: &mut PooledConnection<Client>,
-) -> Result<i8, Error> {
-    let alias_key = &generate_alias_key(name, reference);
-    let search_alias: RedisResult<Vec<String>> = con.smembers(alias_key);
-    match search_alias {
-        Ok(alias) => {
-            let sum = delete_alias(name, con, alias)
-                .unwrap()
-                .add(delete_alias_key(con, alias_key).unwrap());
-            Ok(sum)
+) -> Result<Stored> {
+    let if_match = conditions.if_match;
+    let key = &generate_manifest_key(name, digest);
+    let media_type_key = &generate_media_type_key(name, digest);
+    let metadata_key = &generate_metadata_key(name, digest);
+    let pushed_key = &generate_pushed_key(name);
+    if is_accepted_digest(reference) {
+        let existed: bool = redis_span("EXISTS", key, || con.exists(key))?;
+        let current_digest = existed.then_some(digest);
+        if if_match.is_some_and(|if_match| !if_match.matches(current_digest)) {
+            return Ok(Stored::PreconditionFailed);
+        }
+        expect_types(con, &[(pushed_key, "hash")])?;
+        if metadata.is_some() {
+            expect_types(con, &[(metadata_key, "hash")])?;
+        }
+        let pushed_at = redis_time(con)?;
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .set(key, content)
+            .ignore()
+            .hset(pushed_key, digest, pushed_at)
+            .ignore();
+        if let Some(media_type) = media_type {
+            pipe.set(media_type_key, media_type).ignore();
+        }
+        if let Some(metadata) = metadata {
+            pipe.hset_multiple(metadata_key, &metadata.fields())
+                .ignore();
         }
-        Err(_) => Ok(0),
+        redis_span("MULTI", key, || pipe.query::<()>(con.deref_mut()))?;
+        return Ok(if existed {
+            Stored::Existing
+        } else {
+            Stored::New
+        });
     }
+    let tags_key = &generate_tags_key(name);
+    let alias_key = &generate_alias_key(name, digest);
+    atomically(con, &[key, tags_key, alias_key], |con, pipe| {
+        let existed: bool = con.exists(key)?;
+        let pushed_at = redis_time(con)?;
+        let previous_digest: Option<String> = con.hget(tags_key, reference)?;
+        if if_match.is_some_and(|if_match| !if_match.matches(previous_digest.as_deref())) {
+            redis::cmd("UNWATCH").query::<()>(con)?;
+            return Ok(Some(Stored::PreconditionFailed));
+        }
+        if conditions.immutable
+            && previous_digest
+                .as_deref()
+                .is_some_and(|previous| previous != digest)
+        {
+            redis::cmd("UNWATCH").query::<()>(con)?;
+            return Ok(Some(Stored::TagImmutable));
+        }
+        if let (None, Some(max_tags)) = (&previous_digest, conditions.max_tags) {
+            let tags: usize = con.hlen(tags_key)?;
+            if tags >= max_tags {
+                redis::cmd("UNWATCH").query::<()>(con)?;
+                return Ok(Some(Stored::TagLimitReached));
+            }
+        }
+        let previous_alias_key = previous_digest.map(|digest| generate_alias_key(name, &digest));
+        expect_types(
+            con,
+            &[
+                (key, "string"),
+                (tags_key, "hash"),
+                (pushed_key, "hash"),
+                (alias_key, "set"),
+                (metadata_key, "hash"),
+            ],
+        )?;
+        if let Some(previous_alias_key) = &previous_alias_key {
+            expect_types(con, &[(previous_alias_key, "set")])?;
+            pipe.srem(previous_alias_key, reference).ignore();
+        }
+        pipe.set(key, content).ignore();
+        if let Some(media_type) = media_type {
+            pipe.set(media_type_key, media_type).ignore();
+        }
+        if let Some(metadata) = metadata {
+            pipe.hset_multiple(metadata_key, &metadata.fields())
+                .ignore();
+        }
+        pipe.hset(tags_key, reference, digest)
+            .ignore()
+            .hset_multiple(pushed_key, &[(reference, pushed_at), (digest, pushed_at)])
+            .ignore()
+            .sadd(alias_key, reference)
+            .ignore()
+            .query(con)
+            .map(|result: Option<()>| {
+                result.map(|()| {
+                    if existed {
+                        Stored::Existing
+                    } else {
+                        Stored::New
+                    }
+                })
+            })
+    })
 }
 
-/// Delete alias
-fn delete_alias(name: &str, con: &mut PooledConnection<Client>, alias: Vec<String>) -> Result<i8> {
-    let mut sum: i8 = 0;
-    alias.iter().for_each(|alias_key| {
-        let key_to_be_deleted = generate_manifest_key(name, alias_key);
-        sum += con.del::<String, i8>(key_to_be_deleted).unwrap();
+/// Mirror a stored manifest to the replica, unconditionally as the push
+/// conditions were already checked against the primary
+#[allow(clippy::too_many_arguments)]
+fn mirror_store(
+    replica: &Replica,
+    operation: &'static str,
+    name: &str,
+    reference: &str,
+    digest: &str,
+    content: Vec<u8>,
+    media_type: Option<&str>,
+    metadata: Option<ManifestMetadata>,
+) {
+    let (name, reference, digest) = (name.to_string(), reference.to_string(), digest.to_string());
+    let media_type = media_type.map(str::to_string);
+    replica.mirror(operation, move |con| {
+        store(
+            &name,
+            &reference,
+            &digest,
+            &content,
+            media_type.as_deref(),
+            metadata.as_ref(),
+            &PushConditions::default(),
+            con,
+        )
     });
-    Ok(sum)
 }
 
-/// Delete alias key
-fn delete_alias_key(con: &mut PooledConnection<Client>, alias_key: &str) -> Result<i8> {
-    Ok(con.del::<String, i8>(alias_key.to_string()).unwrap())
+/// Delete a manifest, returning how many keys and tags were removed.
+///
+/// Deleting a tag only unlinks it from its digest, while deleting a digest
+/// removes its content and every tag pointing at it.
+fn delete(name: &str, reference: &str, con: &mut PooledConnection<Client>) -> Result<usize> {
+    let tags_key = &generate_tags_key(name);
+    let pushed_key = &generate_pushed_key(name);
+    if !is_accepted_digest(reference) {
+        return atomically(con, &[tags_key], |con, pipe| {
+            let digest: Option<String> = con.hget(tags_key, reference)?;
+            match digest {
+                Some(digest) => {
+                    let alias_key = &generate_alias_key(name, &digest);
+                    expect_types(con, &[(alias_key, "set"), (pushed_key, "hash")])?;
+                    pipe.hdel(tags_key, reference)
+                        .ignore()
+                        .hdel(pushed_key, reference)
+                        .ignore()
+                        .srem(alias_key, reference)
+                        .ignore()
+                        .query(con)
+                        .map(|()| Some(1))
+                }
+                None => Ok(Some(0)),
+            }
+        });
+    }
+    let key = &generate_manifest_key(name, reference);
+    let alias_key = &generate_alias_key(name, reference);
+    let media_type_key = &generate_media_type_key(name, reference);
+    let metadata_key = &generate_metadata_key(name, reference);
+    atomically(con, &[key, alias_key, tags_key], |con, pipe| {
+        let tags: Vec<String> = con.smembers(alias_key)?;
+        expect_types(con, &[(tags_key, "hash"), (pushed_key, "hash")])?;
+        pipe.del(key).del(alias_key).ignore();
+        pipe.del(media_type_key).ignore();
+        pipe.del(metadata_key).ignore();
+        pipe.hdel(pushed_key, reference).ignore();
+        if !tags.is_empty() {
+            pipe.hdel(tags_key, &tags).ignore();
+            pipe.hdel(pushed_key, &tags).ignore();
+        }
+        pipe.query(con)
+            .map(|result: Option<(usize,)>| result.map(|(deleted,)| deleted + tags.len()))
+    })
 }
 
-/// Remove tag from digest
-fn remove_tag_relation_from_digest(
-    name: &str,
-    reference: &str,
-    con: &mut PooledConnection<Client>,
-    manifest: Manifest,
-) -> Result<i8, Error> {
-    let alias_key = generate_alias_key(name, manifest.config.digest.as_str());
-    let response = con.srem(alias_key, reference).unwrap();
-    Ok(response)
+/// Current time of the redis server, in seconds since the epoch, so every
+/// registry instance records push times from the same clock
+fn redis_time(con: &mut Connection) -> RedisResult<u64> {
+    let (seconds, _microseconds): (u64, u64) = redis::cmd("TIME").query(con)?;
+    Ok(seconds)
+}
+
+/// Run related writes in a `MULTI`/`EXEC` transaction watching `keys`,
+/// retried when one of them changes before the writes are applied
+fn atomically<F, T>(con: &mut PooledConnection<Client>, keys: &[&str], transaction: F) -> Result<T>
+where
+    F: FnMut(&mut Connection, &mut Pipeline) -> RedisResult<Option<T>>,
+{
+    redis_span("MULTI", keys[0], || {
+        redis::transaction(con.deref_mut(), keys, transaction).inspect_err(|_| {
+            // the transaction was abandoned before `EXEC`, which would
+            // have cleared the watched keys
+            let _ = redis::cmd("UNWATCH").query::<()>(con.deref_mut());
+        })
+    })
+    .map_err(Error::from)
+}
+
+/// Check every key either doesn't exist or holds the expected type.
+///
+/// Redis doesn't roll back a transaction when one of its commands fails, so
+/// this runs before queueing any write to avoid leaving partial state.
+fn expect_types(con: &mut Connection, keys: &[(&str, &str)]) -> RedisResult<()> {
+    for (key, expected) in keys {
+        let actual: String = redis::cmd("TYPE").arg(*key).query(con)?;
+        if actual != "none" && actual != *expected {
+            return Err(RedisError::from((
+                ErrorKind::TypeError,
+                "Unexpected key type",
+                format!("{} holds a {} instead of a {}", key, actual, expected),
+            )));
+        }
+    }
+    Ok(())
 }
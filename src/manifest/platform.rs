@@ -0,0 +1,73 @@
+use super::INDEX_MEDIA_TYPE;
+
+use rocket::http::Accept;
+use rocket::serde::json::serde_json;
+use rocket::serde::Deserialize;
+
+/// Media type of the docker equivalent of an image index
+pub const MANIFEST_LIST_MEDIA_TYPE: &str =
+    "application/vnd.docker.distribution.manifest.list.v2+json";
+
+/// Platform an image manifest of an index runs on
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+struct Platform {
+    architecture: String,
+    os: String,
+    #[serde(default)]
+    variant: Option<String>,
+}
+
+/// Manifest of an index, only what's needed to pick a platform
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+struct PlatformManifest {
+    digest: String,
+    #[serde(default)]
+    platform: Option<Platform>,
+}
+
+/// An image index, only what's needed to pick a platform
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+struct PlatformIndex {
+    manifests: Vec<PlatformManifest>,
+}
+
+/// Check if the client accepts image indexes, or docker manifest lists.
+/// Clients not sending `Accept` are assumed to.
+pub fn accepts_index(accept: Option<&Accept>) -> bool {
+    let accept = match accept {
+        Some(accept) => accept,
+        None => return true,
+    };
+    accept.media_types().any(|accepted| {
+        [INDEX_MEDIA_TYPE, MANIFEST_LIST_MEDIA_TYPE]
+            .iter()
+            .any(|index| {
+                let (top, sub) = index.split_once('/').expect("media type");
+                (accepted.top() == "*" || accepted.top() == top)
+                    && (accepted.sub() == "*" || accepted.sub() == sub)
+            })
+    })
+}
+
+/// Digest of the manifest of an index for a platform, as
+/// `<os>/<architecture>[/<variant>]`, e.g. `linux/arm64/v8`. The variant is
+/// only compared when it's given. `None` when no manifest matches.
+pub fn select_platform(index: &[u8], platform: &str) -> Option<String> {
+    let index: PlatformIndex = serde_json::from_slice(index).ok()?;
+    let mut wanted = platform.splitn(3, '/');
+    let (os, architecture, variant) = (wanted.next()?, wanted.next()?, wanted.next());
+    index
+        .manifests
+        .into_iter()
+        .find(|manifest| {
+            manifest.platform.as_ref().is_some_and(|platform| {
+                platform.os == os
+                    && platform.architecture == architecture
+                    && variant.is_none_or(|variant| platform.variant.as_deref() == Some(variant))
+            })
+        })
+        .map(|manifest| manifest.digest)
+}
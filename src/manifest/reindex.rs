@@ -0,0 +1,132 @@
+use super::{
+    generate_alias_key, generate_manifest_key, generate_tags_key, MANIFEST_ALIAS_SUFFIX_KEY,
+    MANIFEST_PREFIX_KEY,
+};
+use crate::tags::{content_digest, is_accepted_digest};
+
+use anyhow::Result;
+
+use redis::{Commands, Connection};
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+
+/// Outcome of rebuilding the alias index
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ReindexReport {
+    /// Repositories whose alias sets were rebuilt
+    pub repositories: usize,
+    /// Tag relations added or removed to match the tags hashes
+    pub repaired_relations: usize,
+    /// Stored manifests whose content doesn't match their digest
+    pub mismatching_digests: Vec<String>,
+    /// Tags pointing at a manifest which isn't stored
+    pub dangling_tags: Vec<String>,
+}
+
+impl fmt::Display for ReindexReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "repositories reindexed: {}", self.repositories)?;
+        writeln!(f, "tag relations repaired: {}", self.repaired_relations)?;
+        writeln!(
+            f,
+            "manifests not matching their digest: {}",
+            self.mismatching_digests.len()
+        )?;
+        for key in &self.mismatching_digests {
+            writeln!(f, "   {}", key)?;
+        }
+        write!(
+            f,
+            "tags pointing at missing manifests: {}",
+            self.dangling_tags.len()
+        )?;
+        for tag in &self.dangling_tags {
+            write!(f, "\n   {}", tag)?;
+        }
+        Ok(())
+    }
+}
+
+/// Keys stored for a single repository
+#[derive(Default)]
+struct RepositoryKeys {
+    digests: BTreeSet<String>,
+    aliased_digests: BTreeSet<String>,
+}
+
+/// Rebuild every `::alias` set from the tags hashes, which are the source of
+/// truth for tags, and check each stored manifest still matches its digest.
+///
+/// Tags pushed while it runs may be missed, run it while the registry is idle.
+pub fn reindex(con: &mut Connection) -> Result<ReindexReport> {
+    let pattern = format!("{}::*", MANIFEST_PREFIX_KEY);
+    let keys: Vec<String> = con.scan_match(&pattern)?.collect();
+    let mut repositories: BTreeMap<String, RepositoryKeys> = BTreeMap::new();
+    let alias_suffix = format!("::{}", MANIFEST_ALIAS_SUFFIX_KEY);
+    for key in &keys {
+        let (name, rest) = match key
+            .strip_prefix(MANIFEST_PREFIX_KEY)
+            .and_then(|key| key.strip_prefix("::"))
+            .and_then(|key| key.split_once("::"))
+        {
+            Some(parts) => parts,
+            None => continue,
+        };
+        let repository = repositories.entry(name.to_string()).or_default();
+        if let Some(digest) = rest.strip_suffix(&alias_suffix) {
+            repository.aliased_digests.insert(digest.to_string());
+        } else if is_accepted_digest(rest) {
+            repository.digests.insert(rest.to_string());
+        }
+    }
+    let mut report = ReindexReport {
+        repositories: repositories.len(),
+        ..ReindexReport::default()
+    };
+    for (name, repository) in &repositories {
+        for digest in &repository.digests {
+            let key = generate_manifest_key(name, digest);
+            // deleted since it was scanned
+            let content: Vec<u8> = match con.get(&key)? {
+                Some(content) => content,
+                None => continue,
+            };
+            let algorithm = digest.split_once(':').map(|(algorithm, _)| algorithm);
+            let computed = algorithm.and_then(|algorithm| content_digest(algorithm, &content));
+            if computed.is_some() && computed.as_deref() != Some(digest.as_str()) {
+                report.mismatching_digests.push(key);
+            }
+        }
+        let tags_key = generate_tags_key(name);
+        let tags: HashMap<String, String> = con.hgetall(&tags_key)?;
+        let mut expected: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        for (tag, digest) in tags {
+            if !repository.digests.contains(&digest) {
+                report.dangling_tags.push(format!("{}:{}", name, tag));
+            }
+            expected.entry(digest).or_default().insert(tag);
+        }
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        let digests: BTreeSet<String> = repository
+            .aliased_digests
+            .iter()
+            .chain(expected.keys())
+            .cloned()
+            .collect();
+        for digest in digests {
+            let alias_key = generate_alias_key(name, &digest);
+            let current: BTreeSet<String> = con.smembers(&alias_key)?;
+            let rebuilt = expected.remove(&digest).unwrap_or_default();
+            report.repaired_relations += current.symmetric_difference(&rebuilt).count();
+            pipe.del(&alias_key).ignore();
+            if !rebuilt.is_empty() {
+                pipe.sadd(&alias_key, rebuilt).ignore();
+            }
+        }
+        pipe.query::<()>(con)?;
+    }
+    report.dangling_tags.sort();
+    Ok(report)
+}
@@ -0,0 +1,173 @@
+use super::{
+    atomically, expect_types, generate_alias_key, generate_pushed_key, generate_tags_key,
+    redis_time, MANIFEST_PREFIX_KEY, MANIFEST_PUSHED_SUFFIX_KEY,
+};
+use crate::config::Config;
+use crate::policy::{is_protected_tag, tag_retention, Retention};
+use crate::replica::Replica;
+
+use anyhow::Result;
+
+use r2d2::{Pool, PooledConnection};
+
+use redis::{Client, Commands};
+
+use rocket::serde::Serialize;
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Tag outside of the retention of its repository
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct ExpiredTag {
+    /// The repository name
+    pub repository: String,
+    /// The tag
+    pub tag: String,
+    /// When the tag was last pushed, in seconds since the epoch
+    pub pushed_at: u64,
+}
+
+/// Every tag the `TAG_RETENTION` rules would remove, by repository then from
+/// the most recently pushed.
+///
+/// Protected tags are neither removed nor counted, and tags pushed before
+/// push times were recorded are left alone.
+pub fn expired_tags(
+    config: &Config,
+    con: &mut PooledConnection<Client>,
+) -> Result<Vec<ExpiredTag>> {
+    let now = redis_time(con)?;
+    let pattern = format!("{}::*::{}", MANIFEST_PREFIX_KEY, MANIFEST_PUSHED_SUFFIX_KEY);
+    let suffix = format!("::{}", MANIFEST_PUSHED_SUFFIX_KEY);
+    let mut names: Vec<String> = con
+        .scan_match::<_, String>(&pattern)?
+        .filter_map(|key| {
+            key.strip_prefix(MANIFEST_PREFIX_KEY)?
+                .strip_prefix("::")?
+                .strip_suffix(&suffix)
+                .map(str::to_string)
+        })
+        .collect();
+    names.sort();
+    let mut expired = vec![];
+    for name in names {
+        let retention = match tag_retention(config, &name) {
+            Some(retention) => retention,
+            None => continue,
+        };
+        let pushed: HashMap<String, u64> = con.hgetall(generate_pushed_key(&name))?;
+        let tagged: HashMap<String, String> = con.hgetall(generate_tags_key(&name))?;
+        let mut tags: Vec<(String, u64)> = pushed
+            .into_iter()
+            .filter(|(tag, _)| tagged.contains_key(tag) && !is_protected_tag(config, tag))
+            .collect();
+        tags.sort_by(|(a, a_pushed), (b, b_pushed)| b_pushed.cmp(a_pushed).then(a.cmp(b)));
+        let expired_tags = tags
+            .into_iter()
+            .enumerate()
+            .filter(|(position, (_, pushed_at))| match retention {
+                Retention::Newest(count) => *position >= count,
+                Retention::Younger(age) => now.saturating_sub(*pushed_at) > age.as_secs(),
+            })
+            .map(|(_, (tag, pushed_at))| ExpiredTag {
+                repository: name.clone(),
+                tag,
+                pushed_at,
+            });
+        expired.extend(expired_tags);
+    }
+    Ok(expired)
+}
+
+/// Remove the tags outside of their repository retention, only logging them
+/// with `TAG_RETENTION_DRY_RUN`.
+///
+/// Removing a tag only unlinks it like a tag delete, a tag pushed again since
+/// it was found expired is kept.
+pub fn sweep(
+    config: &Config,
+    con: &mut PooledConnection<Client>,
+    replica: &Replica,
+) -> Result<Vec<ExpiredTag>> {
+    let mut removed = vec![];
+    for tag in expired_tags(config, con)? {
+        if config.tag_retention_dry_run {
+            log::info!(
+                "tag retention: would remove repository={} tag={}",
+                tag.repository,
+                tag.tag
+            );
+            removed.push(tag);
+        } else if remove(&tag, con)? {
+            let mirrored = tag.clone();
+            replica.mirror("tag retention", move |con| remove(&mirrored, con));
+            log::info!(
+                "tag retention: removed repository={} tag={}",
+                tag.repository,
+                tag.tag
+            );
+            removed.push(tag);
+        }
+    }
+    Ok(removed)
+}
+
+/// Unlink an expired tag from its digest, unless it was pushed again
+fn remove(expired: &ExpiredTag, con: &mut PooledConnection<Client>) -> Result<bool> {
+    let (name, tag) = (&expired.repository, &expired.tag);
+    let tags_key = &generate_tags_key(name);
+    let pushed_key = &generate_pushed_key(name);
+    atomically(con, &[tags_key, pushed_key], |con, pipe| {
+        let pushed_at: Option<u64> = con.hget(pushed_key, tag)?;
+        let digest: Option<String> = con.hget(tags_key, tag)?;
+        match (pushed_at, digest) {
+            (Some(pushed_at), Some(digest)) if pushed_at == expired.pushed_at => {
+                let alias_key = &generate_alias_key(name, &digest);
+                expect_types(con, &[(alias_key, "set")])?;
+                pipe.hdel(tags_key, tag)
+                    .ignore()
+                    .hdel(pushed_key, tag)
+                    .ignore()
+                    .srem(alias_key, tag)
+                    .ignore()
+                    .query(con)
+                    .map(|result: Option<()>| result.map(|()| true))
+            }
+            _ => {
+                redis::cmd("UNWATCH").query::<()>(con)?;
+                Ok(Some(false))
+            }
+        }
+    })
+}
+
+/// Run a tag retention sweep every `interval`, off the request handling
+/// threads
+pub fn spawn_sweeps(
+    connection_pool: Pool<Client>,
+    config: Config,
+    replica: Replica,
+    interval: Duration,
+) {
+    tokio::spawn(async move {
+        let start = tokio::time::Instant::now() + interval;
+        let mut interval = tokio::time::interval_at(start, interval);
+        loop {
+            interval.tick().await;
+            let (connection_pool, config, replica) =
+                (connection_pool.clone(), config.clone(), replica.clone());
+            let swept = tokio::task::spawn_blocking(move || {
+                let mut con = connection_pool.get()?;
+                sweep(&config, &mut con, &replica)
+            })
+            .await;
+            match swept {
+                Ok(Ok(_)) => {}
+                Ok(Err(err)) => log::warn!("couldn't sweep expired tags: {}", err),
+                Err(err) => log::warn!("tag retention sweep stopped: {}", err),
+            }
+        }
+    });
+}
@@ -0,0 +1,356 @@
+use super::{ImageIndex, Manifest};
+use crate::storage::StorageBackend;
+use crate::tags::{is_accepted_digest, sha256_digest};
+use crate::Descriptor;
+
+use regex::Regex;
+
+use rocket::serde::json::serde_json::{self, Map, Value};
+use rocket::serde::Serialize;
+
+/// Media types accepted for an image manifest
+pub const ACCEPTED_MANIFEST_MEDIA_TYPES: [&str; 2] = [
+    "application/vnd.oci.image.manifest.v1+json",
+    "application/vnd.docker.distribution.manifest.v2+json",
+];
+
+/// A single problem found while validating a manifest
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct ValidationProblem {
+    /// Path to the offending field, e.g. `layers[0].digest`
+    pub field: String,
+    /// Human readable description of the problem
+    pub message: String,
+}
+
+/// Result of validating a manifest
+#[derive(Serialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct ValidationReport {
+    /// `true` when no problem was found
+    pub valid: bool,
+    /// Every problem found, in field order
+    pub problems: Vec<ValidationProblem>,
+}
+
+impl From<Vec<ValidationProblem>> for ValidationReport {
+    fn from(problems: Vec<ValidationProblem>) -> Self {
+        ValidationReport {
+            valid: problems.is_empty(),
+            problems,
+        }
+    }
+}
+
+/// Validate the config and every layer descriptor of a manifest
+pub fn validate_descriptors(manifest: &Manifest) -> Vec<ValidationProblem> {
+    let mut problems = Vec::new();
+    validate_descriptor("config", &manifest.config, &mut problems);
+    manifest
+        .layers
+        .iter()
+        .enumerate()
+        .for_each(|(index, layer)| {
+            validate_descriptor(&format!("layers[{}]", index), layer, &mut problems)
+        });
+    problems
+}
+
+/// Check the config and every layer of a manifest are stored with the size
+/// their descriptor gives, skipping malformed digests as
+/// [`validate_descriptors`] reports them
+pub fn validate_blobs(manifest: &Manifest, storage: &dyn StorageBackend) -> Vec<ValidationProblem> {
+    let descriptors = std::iter::once(("config".to_string(), &manifest.config)).chain(
+        manifest
+            .layers
+            .iter()
+            .enumerate()
+            .map(|(index, layer)| (format!("layers[{}]", index), layer)),
+    );
+    let mut problems = Vec::new();
+    for (field, descriptor) in descriptors {
+        if !is_accepted_digest(&descriptor.digest) {
+            continue;
+        }
+        match storage.size(&descriptor.digest) {
+            Ok(size) if size as i64 == descriptor.size => {}
+            Ok(size) => problems.push(problem(
+                &format!("{}.size", field),
+                format!(
+                    "size {} doesn't match the {} bytes stored",
+                    descriptor.size, size
+                ),
+            )),
+            Err(err) => problems.push(problem(&format!("{}.digest", field), err.to_string())),
+        }
+    }
+    problems
+}
+
+/// Check an image manifest has at least a base layer, only artifact
+/// manifests, with an `artifactType`, may have no layers at all
+pub fn validate_layers(manifest: &Manifest) -> Option<ValidationProblem> {
+    if manifest.layers.is_empty() && manifest.artifact_type.is_none() {
+        Some(problem(
+            "layers",
+            "an image manifest must have at least one layer".to_string(),
+        ))
+    } else {
+        None
+    }
+}
+
+/// Validate every manifest descriptor of an image index
+pub fn validate_index(index: &ImageIndex) -> Vec<ValidationProblem> {
+    let mut problems = Vec::new();
+    index
+        .manifests
+        .iter()
+        .enumerate()
+        .for_each(|(index, manifest)| {
+            validate_descriptor(&format!("manifests[{}]", index), manifest, &mut problems)
+        });
+    problems
+}
+
+/// Validate the fields of a descriptor, prefixing problems with `field`
+fn validate_descriptor(
+    field: &str,
+    descriptor: &Descriptor,
+    problems: &mut Vec<ValidationProblem>,
+) {
+    if descriptor.media_type.is_empty() {
+        problems.push(problem(
+            &format!("{}.mediaType", field),
+            "missing media type".to_string(),
+        ));
+    } else if !is_media_type_valid(&descriptor.media_type) {
+        problems.push(problem(
+            &format!("{}.mediaType", field),
+            format!("malformed media type `{}`", descriptor.media_type),
+        ));
+    }
+    if descriptor.digest.is_empty() {
+        problems.push(problem(
+            &format!("{}.digest", field),
+            "missing digest".to_string(),
+        ));
+    } else if !is_accepted_digest(&descriptor.digest) {
+        problems.push(problem(
+            &format!("{}.digest", field),
+            format!("malformed digest `{}`", descriptor.digest),
+        ));
+    }
+    if descriptor.size < 0 {
+        problems.push(problem(
+            &format!("{}.size", field),
+            format!("negative size {}", descriptor.size),
+        ));
+    } else if descriptor.size == 0 && descriptor.digest != sha256_digest(b"") {
+        problems.push(problem(
+            &format!("{}.size", field),
+            "size 0 is only valid for empty content".to_string(),
+        ));
+    }
+}
+
+/// Validate a pushed manifest against the OCI image manifest and image index
+/// JSON schemas, catching what deserializing it lets through or stops at
+/// first, e.g. a `schemaVersion` other than `2` or a mistyped field
+pub fn validate_schema(body: &[u8]) -> Vec<ValidationProblem> {
+    let value: Value = match serde_json::from_slice(body) {
+        Ok(value) => value,
+        Err(err) => return vec![problem("", format!("malformed JSON: {}", err))],
+    };
+    let manifest = match value.as_object() {
+        Some(manifest) => manifest,
+        None => return vec![problem("", "a manifest must be a JSON object".to_string())],
+    };
+    let mut problems = Vec::new();
+    match manifest.get("schemaVersion") {
+        Some(version) if version.as_u64() == Some(2) => {}
+        Some(version) => problems.push(problem(
+            "schemaVersion",
+            format!("schema version must be 2, not {}", version),
+        )),
+        None => problems.push(problem(
+            "schemaVersion",
+            "missing schema version".to_string(),
+        )),
+    }
+    check_string(manifest, "", "mediaType", false, &mut problems);
+    check_string(manifest, "", "artifactType", false, &mut problems);
+    check_annotations(manifest, "", &mut problems);
+    if let Some(subject) = manifest.get("subject") {
+        check_descriptor("subject", subject, &mut problems);
+    }
+    let is_index =
+        manifest.get("manifests").is_some_and(Value::is_array) && !manifest.contains_key("layers");
+    if is_index {
+        check_descriptors(manifest, "manifests", &mut problems);
+    } else {
+        match manifest.get("config") {
+            Some(config) => check_descriptor("config", config, &mut problems),
+            None => problems.push(problem("config", "missing config".to_string())),
+        }
+        check_descriptors(manifest, "layers", &mut problems);
+    }
+    problems
+}
+
+/// Check the descriptors of a required array
+fn check_descriptors(
+    object: &Map<String, Value>,
+    name: &str,
+    problems: &mut Vec<ValidationProblem>,
+) {
+    match object.get(name) {
+        Some(Value::Array(descriptors)) => {
+            for (index, descriptor) in descriptors.iter().enumerate() {
+                check_descriptor(&format!("{}[{}]", name, index), descriptor, problems);
+            }
+        }
+        Some(_) => problems.push(problem(name, "must be an array".to_string())),
+        None => problems.push(problem(name, "missing array".to_string())),
+    }
+}
+
+/// Check a descriptor against the OCI content descriptor schema
+fn check_descriptor(field: &str, descriptor: &Value, problems: &mut Vec<ValidationProblem>) {
+    let descriptor = match descriptor.as_object() {
+        Some(descriptor) => descriptor,
+        None => {
+            problems.push(problem(
+                field,
+                "a descriptor must be a JSON object".to_string(),
+            ));
+            return;
+        }
+    };
+    if let Some(media_type) = check_string(descriptor, field, "mediaType", true, problems) {
+        if !is_media_type_valid(media_type) {
+            problems.push(problem(
+                &format!("{}.mediaType", field),
+                format!("malformed media type `{}`", media_type),
+            ));
+        }
+    }
+    if let Some(digest) = check_string(descriptor, field, "digest", true, problems) {
+        if !is_accepted_digest(digest) {
+            problems.push(problem(
+                &format!("{}.digest", field),
+                format!("malformed digest `{}`", digest),
+            ));
+        }
+    }
+    let size_field = format!("{}.size", field);
+    match descriptor.get("size") {
+        Some(size) if size.is_i64() || size.is_u64() => {
+            if size.as_i64().is_some_and(|size| size < 0) {
+                problems.push(problem(&size_field, format!("negative size {}", size)));
+            }
+        }
+        Some(size) => problems.push(problem(
+            &size_field,
+            format!("size must be an integer, not {}", size),
+        )),
+        None => problems.push(problem(&size_field, "missing size".to_string())),
+    }
+    match descriptor.get("urls") {
+        None | Some(Value::Null) => {}
+        Some(Value::Array(urls)) if urls.iter().all(Value::is_string) => {}
+        Some(_) => problems.push(problem(
+            &format!("{}.urls", field),
+            "must be an array of strings".to_string(),
+        )),
+    }
+    check_annotations(descriptor, field, problems);
+    check_string(descriptor, field, "data", false, problems);
+    check_string(descriptor, field, "artifactType", false, problems);
+    match descriptor.get("platform") {
+        None => {}
+        Some(Value::Object(platform)) => {
+            let platform_field = format!("{}.platform", field);
+            check_string(platform, &platform_field, "architecture", true, problems);
+            check_string(platform, &platform_field, "os", true, problems);
+        }
+        Some(_) => problems.push(problem(
+            &format!("{}.platform", field),
+            "must be a JSON object".to_string(),
+        )),
+    }
+}
+
+/// Check an optional `annotations` map only has string values
+fn check_annotations(
+    object: &Map<String, Value>,
+    field: &str,
+    problems: &mut Vec<ValidationProblem>,
+) {
+    let field = child_field(field, "annotations");
+    match object.get("annotations") {
+        None | Some(Value::Null) => {}
+        Some(Value::Object(annotations)) => {
+            for (key, value) in annotations {
+                if !value.is_string() {
+                    problems.push(problem(
+                        &format!("{}.{}", field, key),
+                        format!("annotation values must be strings, not {}", value),
+                    ));
+                }
+            }
+        }
+        Some(_) => problems.push(problem(&field, "must be a JSON object".to_string())),
+    }
+}
+
+/// Check a field is a string, when `required` or present, returning it
+fn check_string<'a>(
+    object: &'a Map<String, Value>,
+    field: &str,
+    name: &str,
+    required: bool,
+    problems: &mut Vec<ValidationProblem>,
+) -> Option<&'a str> {
+    let field = child_field(field, name);
+    match object.get(name) {
+        Some(Value::String(value)) => Some(value),
+        Some(value) => {
+            problems.push(problem(&field, format!("must be a string, not {}", value)));
+            None
+        }
+        None if required => {
+            problems.push(problem(&field, "missing string".to_string()));
+            None
+        }
+        None => None,
+    }
+}
+
+#[doc(hidden)]
+fn child_field(field: &str, name: &str) -> String {
+    if field.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", field, name)
+    }
+}
+
+/// Verify if the media type complies with [RFC 6838](https://tools.ietf.org/html/rfc6838)
+/// using the regex `^[A-Za-z0-9][A-Za-z0-9!#$&^_.+-]{0,126}/[A-Za-z0-9][A-Za-z0-9!#$&^_.+-]{0,126}$`
+pub fn is_media_type_valid(media_type: &str) -> bool {
+    let regex = Regex::new(
+        r"^[A-Za-z0-9][A-Za-z0-9!#$&^_.+-]{0,126}/[A-Za-z0-9][A-Za-z0-9!#$&^_.+-]{0,126}$",
+    )
+    .unwrap();
+    regex.is_match(media_type)
+}
+
+#[doc(hidden)]
+fn problem(field: &str, message: String) -> ValidationProblem {
+    ValidationProblem {
+        field: field.to_string(),
+        message,
+    }
+}
@@ -0,0 +1,193 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+
+use r2d2::Pool;
+
+use redis::Client;
+
+use rocket::http::ContentType;
+use rocket::{get, State};
+
+use super::manifest::consistency::Findings;
+use super::manifest::{referenced_bytes, stored_manifest_counts};
+use super::storage::Filesystem;
+
+/// How often the gauges are recounted from redis and the storage path
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Gauges of what the registry stores, shared between the handlers keeping
+/// them up to date and the `/metrics` endpoint.
+///
+/// Pushes and deletes update the manifest count as they happen, while a
+/// background task recounts everything every [`REFRESH_INTERVAL`], so
+/// scraping never scans the storage.
+#[derive(Clone, Default)]
+pub struct StorageUsage(Arc<Gauges>);
+
+#[derive(Default)]
+struct Gauges {
+    bytes: AtomicU64,
+    referenced_bytes: AtomicU64,
+    blobs: AtomicU64,
+    manifests: AtomicU64,
+    repositories: AtomicU64,
+    missing_blobs: AtomicU64,
+    size_mismatches: AtomicU64,
+    orphaned_aliases: AtomicU64,
+}
+
+impl StorageUsage {
+    /// Count a manifest which wasn't stored before
+    pub fn manifest_added(&self) {
+        self.0.manifests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a deleted manifest
+    pub fn manifest_removed(&self) {
+        let _ = self
+            .0
+            .manifests
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |manifests| {
+                Some(manifests.saturating_sub(1))
+            });
+    }
+
+    /// Count the discrepancies found by a consistency check
+    pub fn consistency_checked(&self, findings: &Findings) {
+        let counters = [
+            (&self.0.missing_blobs, findings.missing_blobs.len()),
+            (&self.0.size_mismatches, findings.size_mismatches.len()),
+            (&self.0.orphaned_aliases, findings.orphaned_aliases.len()),
+        ];
+        for (counter, found) in counters {
+            counter.fetch_add(found as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Recount every gauge from redis and, when configured, the blobs under
+    /// the storage path
+    pub fn refresh(
+        &self,
+        connection_pool: &Pool<Client>,
+        storage: Option<&Filesystem>,
+    ) -> Result<()> {
+        let mut con = connection_pool.get()?;
+        let (repositories, manifests) = stored_manifest_counts(&mut con)?;
+        let referenced = referenced_bytes(&mut con)?;
+        let (blobs, bytes) = match storage {
+            Some(storage) => storage.usage()?,
+            None => (0, 0),
+        };
+        self.0.repositories.store(repositories, Ordering::Relaxed);
+        self.0.manifests.store(manifests, Ordering::Relaxed);
+        self.0.blobs.store(blobs, Ordering::Relaxed);
+        self.0.bytes.store(bytes, Ordering::Relaxed);
+        self.0.referenced_bytes.store(referenced, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Recount the gauges every [`REFRESH_INTERVAL`], starting right away
+    pub fn spawn_refresh(&self, connection_pool: Pool<Client>, storage: Option<Filesystem>) {
+        let usage = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+            loop {
+                interval.tick().await;
+                let (usage, connection_pool, storage) =
+                    (usage.clone(), connection_pool.clone(), storage.clone());
+                let refreshed = tokio::task::spawn_blocking(move || {
+                    usage.refresh(&connection_pool, storage.as_ref())
+                })
+                .await;
+                if let Ok(Err(err)) = refreshed {
+                    log::warn!("couldn't refresh storage usage: {}", err);
+                }
+            }
+        });
+    }
+}
+
+/// Gauges in the Prometheus text exposition format
+impl fmt::Display for StorageUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let gauges = [
+            (
+                "rregistry_storage_bytes",
+                "Total size in bytes of the blobs under the storage path",
+                &self.0.bytes,
+            ),
+            (
+                "rregistry_referenced_bytes",
+                "Sum of the blob sizes referenced by every manifest, shared blobs counted for each one",
+                &self.0.referenced_bytes,
+            ),
+            (
+                "rregistry_blobs",
+                "Number of blobs under the storage path",
+                &self.0.blobs,
+            ),
+            (
+                "rregistry_manifests",
+                "Number of manifests stored",
+                &self.0.manifests,
+            ),
+            (
+                "rregistry_repositories",
+                "Number of repositories with a manifest",
+                &self.0.repositories,
+            ),
+        ];
+        for (name, help, value) in gauges {
+            writeln!(f, "# HELP {} {}", name, help)?;
+            writeln!(f, "# TYPE {} gauge", name)?;
+            writeln!(f, "{} {}", name, value.load(Ordering::Relaxed))?;
+        }
+        let stored = self.0.bytes.load(Ordering::Relaxed);
+        let referenced = self.0.referenced_bytes.load(Ordering::Relaxed);
+        let ratio = if stored == 0 {
+            0.0
+        } else {
+            referenced as f64 / stored as f64
+        };
+        writeln!(
+            f,
+            "# HELP rregistry_deduplication_ratio Referenced bytes for each stored byte"
+        )?;
+        writeln!(f, "# TYPE rregistry_deduplication_ratio gauge")?;
+        writeln!(f, "rregistry_deduplication_ratio {}", ratio)?;
+        let counters = [
+            (
+                "rregistry_consistency_missing_blobs_total",
+                "Blobs found missing by the consistency checks",
+                &self.0.missing_blobs,
+            ),
+            (
+                "rregistry_consistency_size_mismatches_total",
+                "Blobs found with another size than declared by the consistency checks",
+                &self.0.size_mismatches,
+            ),
+            (
+                "rregistry_consistency_orphaned_aliases_total",
+                "Orphaned alias sets found by the consistency checks",
+                &self.0.orphaned_aliases,
+            ),
+        ];
+        for (name, help, value) in counters {
+            writeln!(f, "# HELP {} {}", name, help)?;
+            writeln!(f, "# TYPE {} counter", name)?;
+            writeln!(f, "{} {}", name, value.load(Ordering::Relaxed))?;
+        }
+        Ok(())
+    }
+}
+
+/// Expose the storage usage gauges to Prometheus
+#[get("/metrics")]
+pub fn metrics(usage: &State<StorageUsage>) -> (ContentType, String) {
+    let content_type = ContentType::with_params("text", "plain", ("version", "0.0.4"));
+    (content_type, usage.to_string())
+}
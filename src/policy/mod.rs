@@ -0,0 +1,129 @@
+use std::time::Duration;
+
+use regex::Regex;
+
+use super::config::Config;
+use super::error::RegistryError;
+
+/// Check a name matches a glob pattern, where `*` matches any run of
+/// characters and `?` a single one, e.g. `internal-*` or `library`
+pub fn matches_glob(pattern: &str, name: &str) -> bool {
+    let mut expression = String::from("^");
+    for character in pattern.chars() {
+        match character {
+            '*' => expression.push_str(".*"),
+            '?' => expression.push('.'),
+            character => expression.push_str(&regex::escape(&character.to_string())),
+        }
+    }
+    expression.push('$');
+    Regex::new(&expression).is_ok_and(|regex| regex.is_match(name))
+}
+
+/// Refuse writes to the repositories matching one of the
+/// `BLOCKED_REPO_PATTERNS`
+pub fn check_not_blocked(config: &Config, name: &str) -> Result<(), RegistryError> {
+    match config
+        .blocked_repo_patterns
+        .iter()
+        .find(|pattern| matches_glob(pattern, name))
+    {
+        Some(pattern) => Err(RegistryError::Denied(format!(
+            "repository `{}` is blocked by `{}`",
+            name, pattern
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Refuse pushes and deletes in the repositories matching one of the
+/// `READONLY_REPOSITORIES`, which stay pullable
+pub fn check_not_read_only(config: &Config, name: &str) -> Result<(), RegistryError> {
+    match config
+        .readonly_repositories
+        .iter()
+        .find(|pattern| matches_glob(pattern, name))
+    {
+        Some(pattern) => Err(RegistryError::Denied(format!(
+            "repository `{}` is read-only by `{}`",
+            name, pattern
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Check a tag matches one of the `IMMUTABLE_TAG_PATTERNS`, so it can't be
+/// moved once pushed
+pub fn is_immutable_tag(config: &Config, reference: &str) -> bool {
+    config
+        .immutable_tag_patterns
+        .iter()
+        .any(|pattern| matches_glob(pattern, reference))
+}
+
+/// How many of the tags of a repository a retention rule keeps
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Retention {
+    /// The given number of most recently pushed tags
+    Newest(usize),
+    /// The tags pushed within the given duration
+    Younger(Duration),
+}
+
+/// Retention of the tags of the repositories matching a glob pattern
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetentionRule {
+    pub pattern: String,
+    pub retention: Retention,
+}
+
+impl RetentionRule {
+    /// Parse `<pattern>=<count>` or `<pattern>=<duration>`, the duration
+    /// ending with `s`, `m`, `h` or `d`, e.g. `ci/*=20` or `nightly/*=30d`.
+    ///
+    /// A count or duration of `0`, which would remove every tag, isn't a
+    /// valid rule.
+    pub fn parse(rule: &str) -> Option<RetentionRule> {
+        let (pattern, retention) = rule.split_once('=')?;
+        let (pattern, retention) = (pattern.trim(), retention.trim());
+        let unit = match retention.chars().last()? {
+            's' => Some(1),
+            'm' => Some(60),
+            'h' => Some(60 * 60),
+            'd' => Some(24 * 60 * 60),
+            _ => None,
+        };
+        let retention = match unit {
+            Some(unit) => retention[..retention.len() - 1]
+                .parse::<u64>()
+                .ok()
+                .filter(|amount| *amount > 0)
+                .and_then(|amount| amount.checked_mul(unit))
+                .map(|seconds| Retention::Younger(Duration::from_secs(seconds)))?,
+            None => Retention::Newest(retention.parse().ok().filter(|count| *count > 0)?),
+        };
+        (!pattern.is_empty()).then(|| RetentionRule {
+            pattern: pattern.to_string(),
+            retention,
+        })
+    }
+}
+
+/// Retention of the tags of a repository, from the first of the
+/// `TAG_RETENTION` rules matching it
+pub fn tag_retention(config: &Config, name: &str) -> Option<Retention> {
+    config
+        .tag_retention
+        .iter()
+        .find(|rule| matches_glob(&rule.pattern, name))
+        .map(|rule| rule.retention)
+}
+
+/// Check a tag matches one of the `TAG_RETENTION_PROTECTED` patterns, so
+/// retention never removes it
+pub fn is_protected_tag(config: &Config, reference: &str) -> bool {
+    config
+        .tag_retention_protected
+        .iter()
+        .any(|pattern| matches_glob(pattern, reference))
+}
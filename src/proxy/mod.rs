@@ -0,0 +1,75 @@
+use std::convert::Infallible;
+
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+
+use super::config::Config;
+
+/// Scheme assumed when the proxy forwards a host without its scheme
+const DEFAULT_FORWARDED_PROTO: &str = "http";
+
+/// Scheme and host clients reach the registry through, e.g.
+/// `https://registry.example.com`, used to build the URLs sent back to them.
+///
+/// It's only known behind a reverse proxy trusted with
+/// `TRUST_FORWARDED_HEADERS`, URLs stay relative otherwise.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExternalOrigin(Option<String>);
+
+impl ExternalOrigin {
+    /// Read the origin from the `X-Forwarded-Proto` and `X-Forwarded-Host`
+    /// headers, when they're trusted. Proxies appending to them are
+    /// honored by keeping the first, client facing, value.
+    pub fn of(request: &Request<'_>) -> ExternalOrigin {
+        let trusted = request
+            .rocket()
+            .state::<Config>()
+            .is_some_and(|config| config.trust_forwarded_headers);
+        if !trusted {
+            return ExternalOrigin(None);
+        }
+        let forwarded = |name| {
+            request
+                .headers()
+                .get_one(name)
+                .and_then(|value| value.split(',').next())
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+        };
+        ExternalOrigin(forwarded("X-Forwarded-Host").map(|host| {
+            let proto = forwarded("X-Forwarded-Proto").unwrap_or(DEFAULT_FORWARDED_PROTO);
+            format!("{}://{}", proto, host)
+        }))
+    }
+
+    /// The external origin or, when it isn't known, the `Host` the request
+    /// was sent to over plain HTTP, for URLs clients need absolute
+    pub fn or_host(request: &Request<'_>) -> ExternalOrigin {
+        match ExternalOrigin::of(request) {
+            ExternalOrigin(None) => ExternalOrigin(
+                request
+                    .headers()
+                    .get_one("Host")
+                    .map(|host| format!("{}://{}", DEFAULT_FORWARDED_PROTO, host)),
+            ),
+            origin => origin,
+        }
+    }
+
+    /// URL of a path of the registry, e.g. `/v2/ubuntu/manifests/latest`
+    pub fn url(&self, path: &str) -> String {
+        match &self.0 {
+            Some(origin) => format!("{}{}", origin, path),
+            None => path.to_string(),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ExternalOrigin {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(ExternalOrigin::of(request))
+    }
+}
@@ -0,0 +1,67 @@
+//! Parsing of full image references, `name[:tag][@digest]`, as used to
+//! copy or mount content from another repository, e.g.
+//! `library/ubuntu:22.04` or `library/ubuntu@sha256:6c3c624b...`
+
+use std::fmt;
+use std::str::FromStr;
+
+use super::error::RegistryError;
+use super::manifest::validate_name;
+use super::tags::{is_tag_name_valid, normalize_digest};
+
+/// An image reference split into its repository, tag and digest
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reference {
+    /// Repository name, e.g. `library/ubuntu`
+    pub repository: String,
+    /// Optional tag, e.g. `22.04`
+    pub tag: Option<String>,
+    /// Optional digest, normalized, e.g. `sha256:6c3c624b...`
+    pub digest: Option<String>,
+}
+
+/// Parse a reference, failing with the OCI error of its invalid part
+impl FromStr for Reference {
+    type Err = RegistryError;
+
+    fn from_str(reference: &str) -> Result<Self, Self::Err> {
+        let (name, digest) = match reference.split_once('@') {
+            Some((name, digest)) => {
+                let digest = normalize_digest(digest).ok_or_else(|| {
+                    RegistryError::DigestInvalid(format!("invalid digest `{}`", digest))
+                })?;
+                (name, Some(digest))
+            }
+            None => (reference, None),
+        };
+        // repository names can't contain `:`, so it always starts the tag
+        let (repository, tag) = match name.split_once(':') {
+            Some((repository, tag)) => {
+                if !is_tag_name_valid(tag) {
+                    return Err(RegistryError::TagInvalid(format!("invalid tag `{}`", tag)));
+                }
+                (repository, Some(tag.to_string()))
+            }
+            None => (name, None),
+        };
+        validate_name(repository)?;
+        Ok(Reference {
+            repository: repository.to_string(),
+            tag,
+            digest,
+        })
+    }
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.repository)?;
+        if let Some(tag) = &self.tag {
+            write!(f, ":{}", tag)?;
+        }
+        if let Some(digest) = &self.digest {
+            write!(f, "@{}", digest)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+
+use r2d2::{Pool, PooledConnection};
+
+use redis::Client;
+
+use super::config::Config;
+
+/// How long to wait for a replica connection before giving up on a write
+const REPLICA_CONNECTION_TIMEOUT: Duration = Duration::from_secs(1);
+/// How many writes may wait to be mirrored, newer ones being dropped once
+/// the replica is that far behind
+const REPLICA_QUEUE_SIZE: usize = 1024;
+
+/// A write waiting to be applied to the replica
+type MirroredWrite = Box<dyn FnOnce(&mut PooledConnection<Client>) -> Result<()> + Send>;
+
+/// Optional secondary redis receiving a best-effort copy of every write,
+/// so a warm standby stays roughly in sync. Reads always go to the primary.
+///
+/// Writes are queued and applied in order by a dedicated thread, so a slow
+/// or unreachable replica never delays the requests writing to the primary.
+#[derive(Clone)]
+pub struct Replica(Option<SyncSender<(&'static str, MirroredWrite)>>);
+
+impl Replica {
+    /// Creates the replica connection pool and the thread mirroring writes
+    /// when a replica is configured.
+    ///
+    /// Connections are established lazily, so an unavailable replica
+    /// doesn't prevent the registry from starting.
+    pub fn new(config: &Config) -> Replica {
+        Replica(
+            config
+                .redis_replica_connection_string
+                .as_deref()
+                .map(|connection_string| {
+                    let pool = Pool::builder()
+                        .connection_timeout(REPLICA_CONNECTION_TIMEOUT)
+                        .build_unchecked(
+                            redis::Client::open(connection_string)
+                                .expect("redis replica connection"),
+                        );
+                    let (sender, receiver) =
+                        mpsc::sync_channel::<(&'static str, MirroredWrite)>(REPLICA_QUEUE_SIZE);
+                    thread::Builder::new()
+                        .name("redis-replica".to_string())
+                        .spawn(move || {
+                            for (operation, write) in receiver {
+                                let result = pool
+                                    .get()
+                                    .map_err(anyhow::Error::from)
+                                    .and_then(|mut con| write(&mut con));
+                                if let Err(err) = result {
+                                    log::warn!(
+                                        "couldn't mirror {} to redis replica: {}",
+                                        operation,
+                                        err
+                                    );
+                                }
+                            }
+                        })
+                        .expect("redis replica thread");
+                    sender
+                }),
+        )
+    }
+
+    /// Queue a write to the replica, logging a warning instead of failing
+    /// when the replica can't be reached, the write fails or too many
+    /// writes are already waiting
+    pub fn mirror<T, F>(&self, operation: &'static str, write: F)
+    where
+        F: FnOnce(&mut PooledConnection<Client>) -> Result<T> + Send + 'static,
+    {
+        if let Some(sender) = &self.0 {
+            let write: MirroredWrite = Box::new(move |con| write(con).map(|_| ()));
+            match sender.try_send((operation, write)) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    log::warn!("redis replica is behind, dropping {}", operation)
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    log::warn!("redis replica stopped, dropping {}", operation)
+                }
+            }
+        }
+    }
+
+    /// Wait until the writes queued so far are applied or given up on, so a
+    /// command exiting right after writing doesn't lose them
+    pub fn flush(&self) {
+        if let Some(sender) = &self.0 {
+            let (applied, flushed) = mpsc::channel();
+            let marker: MirroredWrite =
+                Box::new(move |_| applied.send(()).map_err(anyhow::Error::from));
+            if sender.send(("flush", marker)).is_ok() {
+                // the marker is dropped without answering when the replica
+                // can't be reached
+                let _ = flushed.recv();
+            }
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Data, Request, Response};
+
+/// Header carrying the id correlating a request with the server logs
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+/// Longest id accepted from a client, longer ones are replaced
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// Requests identified so far, so generated ids never repeat
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// Id of the request being handled, the client's `X-Request-Id` or a
+/// generated one, `None` before the [`RequestIds`] fairing ran
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RequestId(Option<String>);
+
+impl RequestId {
+    /// Id of a request, identified by the [`RequestIds`] fairing
+    pub fn of<'r>(request: &'r Request<'_>) -> Option<&'r str> {
+        request.local_cache(RequestId::default).0.as_deref()
+    }
+}
+
+/// Fairing identifying every request, keeping the `X-Request-Id` sent by the
+/// client or generating one, echoing it back and logging it along with the
+/// response status
+pub struct RequestIds;
+
+#[rocket::async_trait]
+impl Fairing for RequestIds {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request ids",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        // only ids which can't tamper with log lines are kept
+        let id = request
+            .headers()
+            .get_one(REQUEST_ID_HEADER)
+            .filter(|id| {
+                !id.is_empty()
+                    && id.len() <= MAX_REQUEST_ID_LENGTH
+                    && id
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+            })
+            .map(str::to_string)
+            .unwrap_or_else(generate_request_id);
+        request.local_cache(|| RequestId(Some(id)));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        if let Some(id) = RequestId::of(request) {
+            log::info!(
+                "request_id={} method={} path={} status={}",
+                id,
+                request.method(),
+                request.uri().path(),
+                response.status().code
+            );
+            response.set_raw_header(REQUEST_ID_HEADER, id.to_string());
+        }
+    }
+}
+
+/// Random 128 bits id, as 32 hexadecimal digits
+fn generate_request_id() -> String {
+    let sequence = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+    let random = RandomState::new();
+    format!(
+        "{:016x}{:016x}",
+        random.hash_one(sequence),
+        random.hash_one(!sequence)
+    )
+}
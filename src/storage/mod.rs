@@ -0,0 +1,293 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use tempfile::NamedTempFile;
+
+use super::blob::{Blob, EMPTY_BLOB_CONTENT, EMPTY_BLOB_DIGEST};
+use super::config::Config;
+use super::error::RegistryError;
+
+/// Directory, under the storage path, holding every blob
+const BLOBS_DIRECTORY: &str = "blobs";
+
+/// How blobs are laid out under the storage path
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum StorageLayout {
+    /// Every blob in the same directory, `blobs/<algorithm>:<hex>`
+    Flat,
+    /// Blobs spread by the first two hex characters of their digest,
+    /// `blobs/<algorithm>/<first two hex>/<hex>`, so no directory grows
+    /// too large
+    #[default]
+    Sharded,
+}
+
+impl StorageLayout {
+    /// Layout named `flat` or `sharded`, as in `STORAGE_LAYOUT`
+    pub fn from_name(name: &str) -> Option<StorageLayout> {
+        match name {
+            "flat" => Some(StorageLayout::Flat),
+            "sharded" => Some(StorageLayout::Sharded),
+            _ => None,
+        }
+    }
+
+    /// Name of the layout, as in `STORAGE_LAYOUT`
+    pub fn name(&self) -> &'static str {
+        match self {
+            StorageLayout::Flat => "flat",
+            StorageLayout::Sharded => "sharded",
+        }
+    }
+}
+
+/// Errors of the storage operations, kept apart from the backend internals
+/// so handlers map them to responses the same way whatever the backend
+#[derive(Debug)]
+pub enum StorageError {
+    /// The blob isn't stored
+    NotFound(String),
+    /// Something is already stored where the blob was being written
+    AlreadyExists(String),
+    /// Reading or writing the storage failed
+    Io(io::Error),
+    /// The backend refused the operation, e.g. a malformed digest
+    Backend(String),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::NotFound(digest) => write!(f, "blob `{}` not found", digest),
+            StorageError::AlreadyExists(path) => write!(f, "`{}` already exists", path),
+            StorageError::Io(err) => write!(f, "{}", err),
+            StorageError::Backend(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<io::Error> for StorageError {
+    fn from(err: io::Error) -> Self {
+        StorageError::Io(err)
+    }
+}
+
+/// A missing blob is unknown to the registry, every other failure is on the
+/// registry side
+impl From<StorageError> for RegistryError {
+    fn from(err: StorageError) -> Self {
+        match err {
+            StorageError::NotFound(digest) => RegistryError::BlobUnknown(digest),
+            err => RegistryError::Unknown(err.to_string()),
+        }
+    }
+}
+
+/// Operations on the stored blobs, whatever the backend storing them, failing
+/// with a [`StorageError`]
+pub trait StorageBackend {
+    /// Check if a blob is already stored
+    fn exists(&self, digest: &str) -> bool;
+
+    /// Read a stored blob
+    #[allow(dead_code)]
+    fn get(&self, digest: &str) -> Result<Vec<u8>, StorageError>;
+
+    /// Size in bytes of a stored blob
+    fn size(&self, digest: &str) -> Result<u64, StorageError>;
+
+    /// Store a blob, unless a blob with the same digest is already stored
+    fn put(&self, blob: &Blob) -> Result<(), StorageError>;
+
+    /// Remove a stored blob, `false` when it wasn't stored
+    fn delete(&self, digest: &str) -> Result<bool, StorageError>;
+}
+
+/// Filesystem backend storing blobs in a content-addressed layout shared by
+/// every repository, sharded by default, so the same layer is stored exactly
+/// once however many repositories reference it
+#[derive(Debug, Clone)]
+pub struct Filesystem {
+    root: PathBuf,
+    layout: StorageLayout,
+}
+
+impl Filesystem {
+    /// Creates the backend storing blobs under `root`, the `STORAGE_PATH`
+    pub fn new<P: AsRef<Path>>(root: P) -> Filesystem {
+        Filesystem {
+            root: root.as_ref().to_path_buf(),
+            layout: StorageLayout::default(),
+        }
+    }
+
+    /// Creates the backend configured by `STORAGE_PATH` and
+    /// `STORAGE_LAYOUT`, `None` when there's no storage path
+    pub fn from_config(config: &Config) -> Option<Filesystem> {
+        config
+            .storage_path
+            .as_deref()
+            .map(|root| Filesystem::new(root).with_layout(config.storage_layout))
+    }
+
+    /// Lay the blobs out with the given layout
+    pub fn with_layout(mut self, layout: StorageLayout) -> Filesystem {
+        self.layout = layout;
+        self
+    }
+
+    /// Path of the blob with the given digest, e.g.
+    /// `blobs/sha256/6c/6c3c624b...` when sharded or `blobs/sha256:6c3c624b...`
+    /// when flat, `None` for malformed digests
+    pub fn blob_path(&self, digest: &str) -> Option<PathBuf> {
+        let (algorithm, encoded) = digest.split_once(':')?;
+        let prefix = encoded.get(..2)?;
+        let blobs = self.root.join(BLOBS_DIRECTORY);
+        Some(match self.layout {
+            StorageLayout::Flat => blobs.join(digest),
+            StorageLayout::Sharded => blobs.join(algorithm).join(prefix).join(encoded),
+        })
+    }
+
+    /// Check blobs can be written, by writing and removing a sentinel file
+    pub fn check_writable(&self) -> Result<(), StorageError> {
+        fs::create_dir_all(&self.root)?;
+        let mut sentinel = NamedTempFile::new_in(&self.root)?;
+        sentinel.write_all(b"ready")?;
+        Ok(sentinel.close()?)
+    }
+
+    /// Count the stored blobs and their total size in bytes
+    pub fn usage(&self) -> Result<(u64, u64), StorageError> {
+        let blobs = self.root.join(BLOBS_DIRECTORY);
+        if !blobs.is_dir() {
+            return Ok((0, 0));
+        }
+        Ok(directory_usage(&blobs)?)
+    }
+
+    /// Temporary file a blob can be streamed into, next to where it's
+    /// stored so [`Filesystem::persist`] only has to rename it
+    pub fn temporary(&self, digest: &str) -> Result<NamedTempFile, StorageError> {
+        let path = self.path_of(digest)?;
+        let directory = path.parent().expect("blob directory");
+        fs::create_dir_all(directory)?;
+        Ok(NamedTempFile::new_in(directory)?)
+    }
+
+    /// Store a blob streamed into a temporary file by renaming it, unless a
+    /// blob with the same digest is already stored. The content must have
+    /// been checked against the digest.
+    pub fn persist(&self, digest: &str, temporary: NamedTempFile) -> Result<PathBuf, StorageError> {
+        let path = self.path_of(digest)?;
+        if path.is_file() {
+            return Ok(path);
+        }
+        temporary
+            .persist(&path)
+            .map_err(|err| match err.error.kind() {
+                io::ErrorKind::AlreadyExists => {
+                    StorageError::AlreadyExists(path.display().to_string())
+                }
+                _ => StorageError::Io(err.error),
+            })?;
+        Ok(path)
+    }
+
+    /// Store the empty blob on its first read, OCI artifacts reference it as
+    /// config without uploading it
+    fn materialize_empty(&self) -> Result<PathBuf, StorageError> {
+        self.put(&Blob::empty())?;
+        self.path_of(EMPTY_BLOB_DIGEST)
+    }
+
+    /// Path of the blob with the given digest, failing for malformed digests
+    fn path_of(&self, digest: &str) -> Result<PathBuf, StorageError> {
+        self.blob_path(digest)
+            .ok_or_else(|| StorageError::Backend(format!("invalid digest `{}`", digest)))
+    }
+}
+
+impl StorageBackend for Filesystem {
+    /// Check if a blob is already stored, the empty blob always is without
+    /// being written until it's read
+    fn exists(&self, digest: &str) -> bool {
+        if digest == EMPTY_BLOB_DIGEST {
+            return true;
+        }
+        self.blob_path(digest).is_some_and(|path| path.is_file())
+    }
+
+    /// Read a stored blob, writing the empty blob on its first read
+    fn get(&self, digest: &str) -> Result<Vec<u8>, StorageError> {
+        let path = if digest == EMPTY_BLOB_DIGEST {
+            self.materialize_empty()?
+        } else {
+            self.path_of(digest)?
+        };
+        fs::read(&path).map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => StorageError::NotFound(digest.to_string()),
+            _ => StorageError::Io(err),
+        })
+    }
+
+    /// Size in bytes of a stored blob, the empty blob always being stored
+    fn size(&self, digest: &str) -> Result<u64, StorageError> {
+        if digest == EMPTY_BLOB_DIGEST {
+            return Ok(EMPTY_BLOB_CONTENT.len() as u64);
+        }
+        match fs::metadata(self.path_of(digest)?) {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                Err(StorageError::NotFound(digest.to_string()))
+            }
+            Err(err) => Err(StorageError::Io(err)),
+        }
+    }
+
+    /// Store a blob, unless a blob with the same digest is already stored.
+    ///
+    /// The content is written to a temporary file first and then renamed,
+    /// so a blob is never seen half written.
+    fn put(&self, blob: &Blob) -> Result<(), StorageError> {
+        let path = self.path_of(&blob.digest)?;
+        if path.is_file() {
+            return Ok(());
+        }
+        let mut temporary = self.temporary(&blob.digest)?;
+        temporary.write_all(&blob.bytes)?;
+        self.persist(&blob.digest, temporary).map(|_| ())
+    }
+
+    /// Remove a stored blob, `false` when it wasn't stored
+    fn delete(&self, digest: &str) -> Result<bool, StorageError> {
+        let path = self.path_of(digest)?;
+        match fs::remove_file(&path) {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(StorageError::Io(err)),
+        }
+    }
+}
+
+/// Count the files under a directory, recursively, and their total size
+fn directory_usage(directory: &Path) -> io::Result<(u64, u64)> {
+    let (mut files, mut bytes) = (0, 0);
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            let (nested_files, nested_bytes) = directory_usage(&entry.path())?;
+            files += nested_files;
+            bytes += nested_bytes;
+        } else if metadata.is_file() {
+            files += 1;
+            bytes += metadata.len();
+        }
+    }
+    Ok((files, bytes))
+}
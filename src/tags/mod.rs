@@ -1,4 +1,18 @@
+//! Validation and normalization of manifest references.
+//!
+//! - Repository names are lowercase only. They are never normalized, a name
+//!   with uppercase letters is rejected with `NAME_INVALID`.
+//! - Tags are case-sensitive, `Latest` and `latest` are different tags, and
+//!   are used exactly as sent.
+//! - Digests are normalized with [`normalize_digest`], so the same content
+//!   always resolves to the same digest.
+
 use regex::Regex;
+use sha2::{Digest, Sha256, Sha512};
+
+/// Registered digest algorithms with the length of their encoded portion,
+/// which is lowercase hex
+const HEX_ENCODED_ALGORITHMS: [(&str, usize); 2] = [("sha256", 64), ("sha512", 128)];
 
 /// Validate tag names using the regex `^[a-zA-Z0-9_][a-zA-Z0-9._-]{0,127}$`
 pub fn is_tag_name_valid(name: &str) -> bool {
@@ -6,8 +20,107 @@ pub fn is_tag_name_valid(name: &str) -> bool {
     regex.is_match(name)
 }
 
-/// Validate digest using the regex `^[a-z0-9]+([+._-][a-z0-9]+)*:[a-zA-Z0-9=_-]+$`
+/// Validate digest using the regex `^[a-z0-9]+([+._-][a-z0-9]+)*:[a-zA-Z0-9=_-]+$`,
+/// the encoded portion of registered algorithms having to be lowercase hex of
+/// their exact length, e.g. 64 characters for `sha256`
 pub fn is_accepted_digest(digest: &str) -> bool {
     let regex = Regex::new(r"^[a-z0-9]+([+._-][a-z0-9]+)*:[a-zA-Z0-9=_-]+$").unwrap();
     regex.is_match(digest)
+        && digest.split_once(':').is_some_and(|(algorithm, encoded)| {
+            match HEX_ENCODED_ALGORITHMS
+                .iter()
+                .find(|(registered, _)| *registered == algorithm)
+            {
+                Some((_, length)) => {
+                    encoded.len() == *length
+                        && encoded
+                            .bytes()
+                            .all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+                }
+                None => true,
+            }
+        })
+}
+
+/// Normalize a digest so the same content always has the same digest:
+/// the algorithm is lowercased, and so is the hex of registered algorithms,
+/// e.g. `SHA256:ABC...` becomes `sha256:abc...`
+///
+/// Returns `None` for malformed digests.
+pub fn normalize_digest(digest: &str) -> Option<String> {
+    let (algorithm, encoded) = digest.split_once(':')?;
+    let algorithm = algorithm.to_ascii_lowercase();
+    let encoded = if HEX_ENCODED_ALGORITHMS
+        .iter()
+        .any(|(registered, _)| *registered == algorithm)
+    {
+        encoded.to_ascii_lowercase()
+    } else {
+        encoded.to_string()
+    };
+    let digest = format!("{}:{}", algorithm, encoded);
+    if is_accepted_digest(&digest) {
+        Some(digest)
+    } else {
+        None
+    }
+}
+
+/// Normalize a manifest reference: digests are normalized with
+/// [`normalize_digest`] while tags, which are case-sensitive, are kept as is
+pub fn normalize_reference(reference: &str) -> String {
+    if reference.contains(':') {
+        normalize_digest(reference).unwrap_or_else(|| reference.to_string())
+    } else {
+        reference.to_string()
+    }
+}
+
+/// Compute the `sha256` digest of some content, e.g. `sha256:6c3c624b...`
+pub fn sha256_digest(content: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(content))
+}
+
+/// Compute the digest of some content with one of the registered algorithms,
+/// `None` when the algorithm isn't supported
+pub fn content_digest(algorithm: &str, content: &[u8]) -> Option<String> {
+    match algorithm {
+        "sha256" => Some(sha256_digest(content)),
+        "sha512" => Some(format!("sha512:{:x}", Sha512::digest(content))),
+        _ => None,
+    }
+}
+
+/// Digest computed as content is streamed, with one of the registered
+/// algorithms
+pub enum ContentHasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl ContentHasher {
+    /// Hasher of the given algorithm, `None` when it isn't supported
+    pub fn new(algorithm: &str) -> Option<ContentHasher> {
+        match algorithm {
+            "sha256" => Some(ContentHasher::Sha256(Sha256::new())),
+            "sha512" => Some(ContentHasher::Sha512(Sha512::new())),
+            _ => None,
+        }
+    }
+
+    /// Hash the next part of the content
+    pub fn update(&mut self, content: &[u8]) {
+        match self {
+            ContentHasher::Sha256(hasher) => hasher.update(content),
+            ContentHasher::Sha512(hasher) => hasher.update(content),
+        }
+    }
+
+    /// Digest of the content hashed so far, as [`content_digest`] computes it
+    pub fn digest(self) -> String {
+        match self {
+            ContentHasher::Sha256(hasher) => format!("sha256:{:x}", hasher.finalize()),
+            ContentHasher::Sha512(hasher) => format!("sha512:{:x}", hasher.finalize()),
+        }
+    }
 }
@@ -0,0 +1,139 @@
+use std::time::{Duration, Instant};
+
+use opentelemetry::propagation::{Extractor, TextMapPropagator};
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::trace::TraceError;
+use opentelemetry::Context;
+use opentelemetry_otlp::WithExportConfig;
+
+use rocket::http::HeaderMap;
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+
+use super::request_id::RequestId;
+
+use tracing::field;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+
+/// Install a tracing subscriber exporting spans through OTLP to `endpoint`,
+/// e.g. `http://localhost:4317`
+pub fn init_otlp(endpoint: &str) -> Result<(), TraceError> {
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .install_batch(opentelemetry::runtime::Tokio)?;
+    // not `try_init`, which would take over the `log` facade rocket logs with
+    tracing::subscriber::set_global_default(
+        tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer)),
+    )
+    .map_err(|err| TraceError::Other(err.into()))
+}
+
+/// Flush the spans which weren't exported yet
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// Trace context propagated by the client through the
+/// [`traceparent`](https://www.w3.org/TR/trace-context/#traceparent-header) header,
+/// along with the id of the request
+pub struct TraceParent {
+    context: Context,
+    request_id: Option<String>,
+}
+
+impl TraceParent {
+    /// Make the current span a child of the client's span, recording the id
+    /// of the request in its `request_id` field so everything traced while
+    /// handling the request carries it
+    pub fn adopt(self) {
+        let span = tracing::Span::current();
+        span.set_parent(self.context);
+        if let Some(request_id) = &self.request_id {
+            span.record("request_id", &request_id.as_str());
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for TraceParent {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let context = TraceContextPropagator::new().extract(&HeaderExtractor(request.headers()));
+        Outcome::Success(TraceParent {
+            context,
+            request_id: RequestId::of(request).map(str::to_string),
+        })
+    }
+}
+
+/// Headers carrying the W3C trace context
+const TRACE_CONTEXT_HEADERS: [&str; 2] = ["traceparent", "tracestate"];
+
+#[doc(hidden)]
+struct HeaderExtractor<'h>(&'h HeaderMap<'h>);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get_one(key)
+    }
+
+    // rocket doesn't lend out header names, so only the trace context ones
+    // are listed
+    fn keys(&self) -> Vec<&str> {
+        TRACE_CONTEXT_HEADERS
+            .iter()
+            .copied()
+            .filter(|name| self.0.contains(*name))
+            .collect()
+    }
+}
+
+/// Run a redis operation inside a child span recording the command, the key
+/// and how long it took
+pub fn redis_span<T, F>(command: &str, key: &str, operation: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    let span = tracing::info_span!("redis", command, key, latency_us = field::Empty);
+    let _entered = span.enter();
+    let start = Instant::now();
+    let result = operation();
+    span.record("latency_us", &(start.elapsed().as_micros() as u64));
+    result
+}
+
+/// Durations of the steps of a request, sent in the
+/// [`Server-Timing`](https://www.w3.org/TR/server-timing/) header when
+/// `DEBUG_TIMING` is enabled
+#[derive(Debug, Default, Clone)]
+pub struct ServerTiming(Vec<(&'static str, Duration)>);
+
+impl ServerTiming {
+    /// Run a step of the request, recording how long it took
+    pub fn time<T, F>(&mut self, step: &'static str, operation: F) -> T
+    where
+        F: FnOnce() -> T,
+    {
+        let start = Instant::now();
+        let result = operation();
+        self.0.push((step, start.elapsed()));
+        result
+    }
+
+    /// Value of the `Server-Timing` header, e.g. `resolve;dur=0.210, fetch;dur=0.480`
+    /// with durations in milliseconds
+    pub fn header_value(&self) -> String {
+        self.0
+            .iter()
+            .map(|(step, duration)| format!("{};dur={:.3}", step, duration.as_secs_f64() * 1000.0))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}